@@ -0,0 +1,217 @@
+extern crate lapin;
+extern crate pg_amqp_bridge as bridge;
+extern crate tokio;
+extern crate tokio_postgres;
+
+use std::env;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bridge::{Binding, Bridge, BridgeHandle};
+use lapin::{Channel, Connection, ConnectionProperties};
+use lapin::message::DeliveryResult;
+use lapin::options::{BasicConsumeOptions, QueueDeclareOptions, QueueDeleteOptions};
+use lapin::types::FieldTable;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_postgres::{Client, NoTls};
+
+const WARM_UP_BODY: &str = "warm-up";
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/*
+ * End to end benchmark of the bridge: NOTIFY on PostgreSQL until the message is consumed from RabbitMQ, spread over
+ * a number of bindings. By default the bridge runs in this process, with BENCH_BRIDGE set to the path of a
+ * pg-amqp-bridge binary that one is spawned instead, e.g. one built from an older commit to compare both.
+*/
+struct Settings {
+  pg_uri: String,
+  amqp_uri: String,
+  messages: usize,
+  binding_counts: Vec<usize>,
+  external_bridge: Option<String>
+}
+
+impl Settings {
+  fn new() -> Settings {
+    Settings {
+      pg_uri: env::var("BENCH_PG_URI").unwrap_or_else(|_| "postgres://postgres@localhost".to_string()),
+      amqp_uri: env::var("BENCH_AMQP_URI").unwrap_or_else(|_| "amqp://localhost//".to_string()),
+      messages: env::var("BENCH_MESSAGES").ok()
+        .map(|messages| messages.parse().expect("BENCH_MESSAGES must be a number"))
+        .unwrap_or(10000),
+      binding_counts: env::var("BENCH_BINDINGS").unwrap_or_else(|_| "1,10,100".to_string())
+        .split(',')
+        .map(|count| count.trim().parse().expect("BENCH_BINDINGS must be a comma separated list of numbers"))
+        .collect(),
+      external_bridge: env::var("BENCH_BRIDGE").ok()
+    }
+  }
+}
+
+enum RunningBridge {
+  Embedded(BridgeHandle),
+  External(Child)
+}
+
+impl RunningBridge {
+  fn start(settings: &Settings, bindings: &[(String, String)]) -> RunningBridge {
+    match settings.external_bridge {
+      Some(ref path) => {
+        let bridge_channels = bindings.iter()
+          .map(|(pg_channel, queue)| format!("{}:{}", pg_channel, queue))
+          .collect::<Vec<String>>()
+          .join(",");
+        RunningBridge::External(Command::new(path)
+          .env("POSTGRESQL_URI", &settings.pg_uri)
+          .env("AMQP_URI", &settings.amqp_uri)
+          .env("BRIDGE_CHANNELS", bridge_channels)
+          .spawn()
+          .unwrap_or_else(|e| panic!("Could not start {}: {}", path, e)))
+      },
+      None => RunningBridge::Embedded(Bridge::builder()
+        .postgresql_uri(&settings.pg_uri)
+        .amqp_uri(&settings.amqp_uri)
+        .bindings(bindings.iter().map(|(pg_channel, queue)| Binding::new(pg_channel, queue)).collect())
+        .start()
+        .unwrap())
+    }
+  }
+
+  fn stop(self) {
+    match self {
+      RunningBridge::Embedded(handle) => {
+        handle.stop();
+        handle.join().unwrap();
+      },
+      RunningBridge::External(mut child) => {
+        child.kill().unwrap();
+        child.wait().unwrap();
+      }
+    }
+  }
+}
+
+struct Report {
+  bindings: usize,
+  messages: usize,
+  elapsed: Duration,
+  // Microseconds from the NOTIFY to the delivery, sorted
+  latencies: Vec<u64>
+}
+
+impl Report {
+  fn print(&self) {
+    let percentile = |p: usize| self.latencies[(self.latencies.len() - 1) * p / 100] as f64 / 1000.0;
+    println!("{:>8} bindings {:>10.0} msg/s   latency p50 {:>8.2} ms   p99 {:>8.2} ms   max {:>8.2} ms",
+             self.bindings, self.messages as f64 / self.elapsed.as_secs_f64(),
+             percentile(50), percentile(99), percentile(100));
+  }
+}
+
+fn main() {
+  let settings = Settings::new();
+  let runtime = Runtime::new().unwrap();
+  println!("{} messages, {} bridge", settings.messages,
+           settings.external_bridge.as_deref().unwrap_or("embedded"));
+
+  for &count in &settings.binding_counts {
+    let bindings: Vec<(String, String)> = (0..count)
+      .map(|i| (format!("bench_channel_{}", i), format!("bench_queue_{}", i)))
+      .collect();
+    let (amqp, deliveries) = runtime.block_on(consume(&settings.amqp_uri, &bindings));
+    let running_bridge = RunningBridge::start(&settings, &bindings);
+
+    let report = runtime.block_on(run(&settings, &bindings, deliveries));
+    report.print();
+
+    running_bridge.stop();
+    runtime.block_on(delete_queues(amqp, &bindings));
+  }
+}
+
+fn now_micros() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+// Declares the queues and consumes them, every delivery sends its binding index and latency
+async fn consume(amqp_uri: &str, bindings: &[(String, String)]) -> ((Connection, Channel), UnboundedReceiver<(usize, Option<u64>)>) {
+  let connection = Connection::connect(amqp_uri, ConnectionProperties::default()).await.unwrap();
+  let channel = connection.create_channel().await.unwrap();
+  let (sender, deliveries) = mpsc::unbounded_channel();
+  for (i, (_, queue)) in bindings.iter().enumerate() {
+    channel.queue_declare(queue, QueueDeclareOptions::default(), FieldTable::default()).await.unwrap();
+    let consumer = channel.basic_consume(queue, "", BasicConsumeOptions{ no_ack: true, ..BasicConsumeOptions::default() }, FieldTable::default()).await.unwrap();
+    let sender: UnboundedSender<(usize, Option<u64>)> = sender.clone();
+    consumer.set_delegate(move |delivery: DeliveryResult| {
+      let sender = sender.clone();
+      async move {
+        if let Ok(Some(delivery)) = delivery {
+          let body = String::from_utf8_lossy(&delivery.data);
+          let latency = body.parse::<u64>().ok().map(|sent| now_micros().saturating_sub(sent));
+          let _ = sender.send((i, latency));
+        }
+      }
+    });
+  }
+  ((connection, channel), deliveries)
+}
+
+async fn delete_queues((connection, channel): (Connection, Channel), bindings: &[(String, String)]) {
+  for (_, queue) in bindings {
+    channel.queue_delete(queue, QueueDeleteOptions::default()).await.unwrap();
+  }
+  connection.close(200, "").await.unwrap();
+}
+
+async fn notify(client: &Client, pg_channel: &str, body: &str) {
+  client.execute("SELECT pg_notify($1, $2)", &[&pg_channel, &body]).await.unwrap();
+}
+
+async fn run(settings: &Settings, bindings: &[(String, String)], mut deliveries: UnboundedReceiver<(usize, Option<u64>)>) -> Report {
+  let (client, connection) = tokio_postgres::connect(&settings.pg_uri, NoTls).await.unwrap();
+  tokio::spawn(connection);
+
+  // Until every binding has delivered a message the bridge isn't ready
+  let mut warmed_up = vec![false; bindings.len()];
+  while warmed_up.contains(&false) {
+    for (i, (pg_channel, _)) in bindings.iter().enumerate() {
+      if !warmed_up[i] {
+        notify(&client, pg_channel, WARM_UP_BODY).await;
+      }
+    }
+    let deadline = tokio::time::sleep(Duration::from_millis(500));
+    tokio::pin!(deadline);
+    loop {
+      tokio::select! {
+        Some((i, _)) = deliveries.recv() => warmed_up[i] = true,
+        _ = &mut deadline => break
+      }
+    }
+  }
+
+  let started = Instant::now();
+  let sending = {
+    let client = &client;
+    async move {
+      for n in 0..settings.messages {
+        let (ref pg_channel, _) = bindings[n % bindings.len()];
+        notify(client, pg_channel, &format!("bench|{}", now_micros())).await;
+      }
+    }
+  };
+  let receiving = async {
+    let mut latencies = Vec::with_capacity(settings.messages);
+    while latencies.len() < settings.messages {
+      match tokio::time::timeout(RECEIVE_TIMEOUT, deliveries.recv()).await {
+        Ok(Some((_, Some(latency)))) => latencies.push(latency),
+        Ok(Some((_, None))) => {},
+        _ => panic!("Received {} of {} messages", latencies.len(), settings.messages)
+      }
+    }
+    latencies
+  };
+  let ((), mut latencies) = tokio::join!(sending, receiving);
+  let elapsed = started.elapsed();
+  latencies.sort_unstable();
+  Report{ bindings: bindings.len(), messages: settings.messages, elapsed, latencies }
+}
@@ -0,0 +1,339 @@
+use lapin::{Channel, Connection, ConnectionProperties, ConnectionState};
+use lapin::options::{ConfirmSelectOptions, ExchangeDeclareOptions, QueueDeclareOptions};
+use lapin::tcp::{AMQPUriTcpExt, NativeTlsConnector, TcpStream};
+use lapin::types::FieldTable;
+use lapin::uri::{AMQPScheme, AMQPUri};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use super::Type;
+use super::error::BridgeError;
+use super::shutdown::Shutdown;
+use super::tls;
+use super::topology::{self, Kind, Topology};
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// The reply code of the broker to a passive declare of an exchange of another kind
+const PRECONDITION_FAILED: u16 = 406;
+
+/*
+ * TLS settings of the amqps:// connections, the certificates and key are PEM and the key in PKCS#8. With a client
+ * certificate the broker can also authenticate the bridge through `auth_mechanism=external` in the URI.
+*/
+#[derive(Debug, Clone)]
+pub struct AmqpTls{
+  pub root_cert: Option<String>,
+  pub client_cert: Option<String>,
+  pub client_key: Option<String>,
+  // Name the server certificate is checked against instead of the URI host, e.g. when connecting through an IP
+  pub server_name: Option<String>,
+  // Without verifying, any server certificate is taken, only meant for testing
+  pub verify: bool
+}
+
+impl Default for AmqpTls {
+  fn default() -> AmqpTls {
+    AmqpTls{ root_cert: None, client_cert: None, client_key: None, server_name: None, verify: true }
+  }
+}
+
+// How the AMQP URIs are tried when connecting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeOrder {
+  // The first URI is preferred, the next ones are only used while the previous ones are down
+  Ordered,
+  // Spreads the bridges over the nodes of a cluster
+  Shuffled
+}
+
+// Opens the connections to the nodes of the AMQP URIs, its settings are checked once when the bridge starts
+#[derive(Clone)]
+pub struct AmqpConnector{
+  nodes: Vec<AMQPUri>,
+  order: NodeOrder,
+  tls: NativeTlsConnector,
+  server_name: Option<String>
+}
+
+impl AmqpConnector {
+  pub fn new(amqp_uris: &[String], order: NodeOrder, amqp_tls: &AmqpTls) -> Result<AmqpConnector, BridgeError> {
+    if amqp_uris.is_empty() {
+      return Err(BridgeError::Config("The bridge needs an AMQP URI".to_string()));
+    }
+    let nodes = amqp_uris.iter()
+      .map(|amqp_uri| amqp_uri.trim().parse().map_err(|e| BridgeError::Config(format!("Invalid AMQP URI {:?}: {}", amqp_uri, e))))
+      .collect::<Result<Vec<AMQPUri>, BridgeError>>()?;
+    let mut builder = tls::connector_builder("AMQP", amqp_tls.root_cert.as_deref(), amqp_tls.client_cert.as_deref(), amqp_tls.client_key.as_deref())?;
+    builder.danger_accept_invalid_certs(!amqp_tls.verify);
+    builder.danger_accept_invalid_hostnames(!amqp_tls.verify);
+    Ok(AmqpConnector{ nodes, order, tls: tls::build("AMQP", &builder)?, server_name: amqp_tls.server_name.clone() })
+  }
+
+  // amqps:// goes through our TLS connector instead of the default one of lapin
+  async fn connect(&self, uri: &AMQPUri) -> lapin::Result<Connection> {
+    let tls = self.tls.clone();
+    let server_name = self.server_name.clone().unwrap_or_else(|| uri.authority.host.clone());
+    let connect = move |uri: &AMQPUri| match uri.scheme {
+      AMQPScheme::AMQP => uri.connect(),
+      AMQPScheme::AMQPS => {
+        let address = format!("{}:{}", uri.authority.host, uri.authority.port);
+        let stream = match uri.query.connection_timeout {
+          Some(timeout) => TcpStream::connect_timeout(address, Duration::from_millis(timeout)),
+          None => TcpStream::connect(address)
+        }?;
+        let stream = stream.into_native_tls(&tls, &server_name)?;
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+      }
+    };
+    Connection::connector(uri.clone(), Box::new(connect), ConnectionProperties::default()).await
+  }
+
+  // The nodes in the order they're tried this time
+  fn nodes(&self) -> Vec<&AMQPUri> {
+    let mut nodes: Vec<&AMQPUri> = self.nodes.iter().collect();
+    if self.order == NodeOrder::Shuffled {
+      fastrand::shuffle(&mut nodes);
+    }
+    nodes
+  }
+}
+
+// An open connection and the node it's connected to, as host:port
+#[derive(Clone)]
+pub struct AmqpNode{
+  pub connection: Arc<Connection>,
+  pub name: String
+}
+
+/*
+ * The AMQP connection shared by all the bindings, each binding publishes through its own channel.
+ * The connection is reopened by the first binding that finds it lost, the others wait for it.
+*/
+pub struct AmqpConnection{
+  connector: AmqpConnector,
+  topology: Topology,
+  node: Mutex<Option<AmqpNode>>,
+  // Stops the reconnection attempts
+  shutdown: Shutdown
+}
+
+impl AmqpConnection {
+  pub fn new(connector: AmqpConnector, topology: Topology, shutdown: Shutdown) -> AmqpConnection {
+    AmqpConnection{ connector, topology, node: Mutex::new(None), shutdown }
+  }
+
+  pub async fn get(&self) -> Result<AmqpNode, BridgeError> {
+    let mut node = self.node.lock().await;
+    self.open(&mut node, true).await
+  }
+
+  /*
+   * Tries every node once for the bindings that spool their messages instead of waiting for the broker, fails right
+   * away while another binding is reconnecting.
+  */
+  pub async fn try_get(&self) -> Result<AmqpNode, BridgeError> {
+    match self.node.try_lock() {
+      Ok(mut node) => self.open(&mut node, false).await,
+      Err(_) => Err(BridgeError::Amqp(lapin::Error::InvalidConnectionState(ConnectionState::Connecting)))
+    }
+  }
+
+  // The topology is declared on every new connection, the node it failed on may have lost it
+  async fn open(&self, node: &mut Option<AmqpNode>, wait: bool) -> Result<AmqpNode, BridgeError> {
+    match *node {
+      Some(ref open) if open.connection.status().connected() => Ok(open.clone()),
+      _ => {
+        let open = if wait {
+          wait_for_amqp_connection(&self.connector, &self.shutdown).await?
+        } else {
+          connect_to_any(&self.connector, &self.shutdown).await?
+        };
+        if !self.topology.is_empty() {
+          if let Err(e) = topology::declare(&open.connection, &self.topology).await {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, open.connection.close(200, "")).await;
+            return Err(e);
+          }
+        }
+        *node = Some(open.clone());
+        Ok(open)
+      }
+    }
+  }
+
+  /*
+   * Fails over to another node when a publish finds the connection lost, the connection can still look open,
+   * e.g. on an IO error. When another binding already replaced it, its new connection is taken. Without waiting
+   * every node is only tried once.
+  */
+  pub async fn replace(&self, lost: &AmqpNode, wait: bool) -> Result<AmqpNode, BridgeError> {
+    {
+      let mut node = self.node.lock().await;
+      if node.as_ref().is_some_and(|open| Arc::ptr_eq(&open.connection, &lost.connection)) {
+        warn!("Lost the connection to AMQP server {}", lost.name);
+        *node = None;
+        // The broker may not answer anymore
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, lost.connection.close(200, "")).await;
+      }
+    }
+    if wait { self.get().await } else { self.try_get().await }
+  }
+
+  pub async fn close(&self) {
+    if let Some(node) = self.node.lock().await.take() {
+      match tokio::time::timeout(CLOSE_TIMEOUT, node.connection.close(200, "")).await {
+        Ok(Err(e)) => error!("{:?}", e),
+        Err(_) => error!("The AMQP server {} didn't answer the close", node.name),
+        Ok(Ok(())) => {}
+      }
+      println!("Closed the AMQP connection");
+    }
+  }
+}
+
+// Every node is tried before waiting for the next round, until the shutdown is requested
+pub async fn wait_for_amqp_connection(connector: &AmqpConnector, shutdown: &Shutdown) -> Result<AmqpNode, BridgeError> {
+  println!("Attempting to obtain connection on AMQP server..");
+  let mut i = 1;
+  loop {
+    match connect_to_any(connector, shutdown).await {
+      Err(BridgeError::Stopped) => return Err(BridgeError::Stopped),
+      Err(_) => {},
+      Ok(node) => return Ok(node)
+    }
+    let time = Duration::from_secs(i);
+    println!("Retrying the AMQP connection in {:?} seconds..", time.as_secs());
+    shutdown.sleep(time).await;
+    i *= 2;
+    if i > 32 { i = 1 };
+  }
+}
+
+// Tries every node once, the error of the last one is returned when none of them could be reached
+async fn connect_to_any(connector: &AmqpConnector, shutdown: &Shutdown) -> Result<AmqpNode, BridgeError> {
+  let mut error = lapin::Error::InvalidConnectionState(ConnectionState::Closed);
+  for uri in connector.nodes() {
+    if shutdown.is_requested() {
+      return Err(BridgeError::Stopped);
+    }
+    let name = format!("{}:{}", uri.authority.host, uri.authority.port);
+    match connector.connect(uri).await {
+      Ok(connection) => {
+        println!("Connection to AMQP server {} successful", name);
+        return Ok(AmqpNode{ connection: Arc::new(connection), name });
+      },
+      Err(e) => {
+        println!("AMQP server {}: {:?}", name, e);
+        error = e;
+      }
+    }
+  }
+  Err(BridgeError::Amqp(error))
+}
+
+/*
+ * Finds the amqp entity type(Queue or Exchange) with passive declares, an exchange is preferred when both exist.
+ * Bindings that state their type skip this, see check_amqp_entity_type.
+*/
+pub async fn get_amqp_entity_type(connection: &Connection, amqp_entity: &str) -> Result<Type, BridgeError> {
+  if amqp_entity_exists(connection, amqp_entity, &Type::Exchange, None).await? {
+    return Ok(Type::Exchange);
+  }
+  if amqp_entity_exists(connection, amqp_entity, &Type::Queue, None).await? {
+    return Ok(Type::Queue);
+  }
+  Err(BridgeError::EntityNotFound(amqp_entity.to_string()))
+}
+
+/*
+ * The other type is only looked up to tell a mismatch from a missing entity.
+ * A stated exchange kind is passed to the passive declare, the broker refusing it when the exchange is of another kind.
+*/
+pub async fn check_amqp_entity_type(connection: &Connection, amqp_entity: &str, entity_type: &Type,
+                                    exchange_kind: Option<Kind>) -> Result<(), BridgeError> {
+  if amqp_entity_exists(connection, amqp_entity, entity_type, exchange_kind).await? {
+    return Ok(());
+  }
+  let other_type = match entity_type {
+    Type::Exchange => Type::Queue,
+    Type::Queue => Type::Exchange
+  };
+  if amqp_entity_exists(connection, amqp_entity, &other_type, None).await? {
+    return Err(BridgeError::EntityTypeMismatch(amqp_entity.to_string(), other_type, None));
+  }
+  Err(BridgeError::EntityNotFound(amqp_entity.to_string()))
+}
+
+/*
+ * A passive declare on its own channel, since the broker closes the channel when the entity doesn't exist.
+ * Only the broker refusal means it doesn't exist, a lost connection is returned as an error.
+*/
+async fn amqp_entity_exists(connection: &Connection, amqp_entity: &str, entity_type: &Type,
+                            exchange_kind: Option<Kind>) -> Result<bool, BridgeError> {
+  let channel = connection.create_channel().await?;
+  let declared = match entity_type {
+    Type::Exchange => {
+      let passive_exchange = ExchangeDeclareOptions{ passive: true, ..ExchangeDeclareOptions::default() };
+      channel.exchange_declare(amqp_entity, exchange_kind.unwrap_or_default().exchange_kind(), passive_exchange, FieldTable::default())
+        .await.map(|_| ())
+    },
+    Type::Queue => {
+      let passive_queue = QueueDeclareOptions{ passive: true, ..QueueDeclareOptions::default() };
+      channel.queue_declare(amqp_entity, passive_queue, FieldTable::default()).await.map(|_| ())
+    }
+  };
+  match declared {
+    Ok(()) => {
+      close_channel(&channel).await;
+      Ok(true)
+    },
+    Err(lapin::Error::ProtocolError(e)) if exchange_kind.is_some() && e.get_id() == PRECONDITION_FAILED =>
+      Err(BridgeError::EntityTypeMismatch(amqp_entity.to_string(), Type::Exchange, exchange_kind)),
+    Err(lapin::Error::ProtocolError(_)) => Ok(false),
+    Err(e) => Err(BridgeError::Amqp(e))
+  }
+}
+
+// In confirm mode the broker acks or nacks every message
+pub async fn open_publisher_channel(connection: &Connection, confirm: bool) -> Result<Channel, BridgeError> {
+  let channel = connection.create_channel().await?;
+  if confirm {
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+  }
+  Ok(channel)
+}
+
+pub async fn close_channel(channel: &Channel) {
+  if channel.status().connected() {
+    if let Err(e) = channel.close(200, "").await {
+      error!("{:?}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn amqp_connector_works() {
+    let uris = vec!["amqp://node1//".to_string(), " amqps://node2//?auth_mechanism=external".to_string(), "amqp://node3:5673//".to_string()];
+    let ordered = AmqpConnector::new(&uris, NodeOrder::Ordered, &AmqpTls::default()).unwrap();
+    let hosts = |nodes: Vec<&AMQPUri>| nodes.iter().map(|uri| uri.authority.host.clone()).collect::<Vec<String>>();
+    assert_eq!(vec!["node1", "node2", "node3"], hosts(ordered.nodes()));
+    let shuffled = AmqpConnector::new(&uris, NodeOrder::Shuffled, &AmqpTls::default()).unwrap();
+    let mut shuffled_hosts = hosts(shuffled.nodes());
+    shuffled_hosts.sort();
+    assert_eq!(vec!["node1", "node2", "node3"], shuffled_hosts);
+  }
+
+  #[test]
+  fn amqp_connector_fails_on_invalid_settings() {
+    let uris = vec!["amqps://localhost//".to_string()];
+    assert!(AmqpConnector::new(&[], NodeOrder::Ordered, &AmqpTls::default()).is_err());
+    assert!(AmqpConnector::new(&["amqp://localhost//".to_string(), "rabbitmq".to_string()], NodeOrder::Ordered, &AmqpTls::default()).is_err());
+    assert!(AmqpConnector::new(&uris, NodeOrder::Ordered, &AmqpTls{ root_cert: Some("not a certificate".to_string()), ..AmqpTls::default() }).is_err());
+    assert!(AmqpConnector::new(&uris, NodeOrder::Ordered, &AmqpTls{ client_cert: Some("a certificate".to_string()), ..AmqpTls::default() }).is_err());
+  }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_postgres::Client;
+
+use super::{Binding, wait_for_wake_up};
+use super::properties::Properties;
+use super::shutdown::Shutdown;
+use super::publisher::Publisher;
+
+const CDC_BATCH_SIZE: i32 = 1000;
+
+// Type oids of the values that are kept as json literals
+const BOOL_OID: u32 = 16;
+const INT8_OID: u32 = 20;
+const INT2_OID: u32 = 21;
+const INT4_OID: u32 = 23;
+const JSON_OID: u32 = 114;
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+const NUMERIC_OID: u32 = 1700;
+const JSONB_OID: u32 = 3802;
+
+struct Relation{
+  table: String,
+  columns: Vec<(String, u32)>
+}
+
+#[derive(Debug, PartialEq)]
+struct RowChange{
+  table: String,
+  event: &'static str,
+  row: String
+}
+
+impl RowChange {
+  // Same routing key as the rabbitmq.on_row_change trigger
+  fn routing_key(&self) -> String {
+    format!("row_change.table-{}.event-{}", self.table, self.event)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+enum PgOutputMessage{
+  Change(RowChange),
+  Commit,
+  Other
+}
+
+/*
+ * Publishes the row changes decoded from the binding logical replication slot, the slot is created with the
+ * pgoutput plugin if it doesn't exist and only the tables of the publication are decoded.
+ * The changes are peeked and the slot is advanced only past the transactions whose changes were all published(or taken
+ * by a fallback), so nothing is lost while the bridge is down or shutting down.
+*/
+pub async fn relay_changes(pg_client: &Arc<Client>, publisher: &mut Publisher, binding: &Binding, slot: &str, notifications: &mut UnboundedReceiver<String>, shutdown: &Shutdown){
+  let publication = binding.options.publication.as_deref().unwrap_or(slot);
+  let poll_interval = Duration::from_secs(binding.options.poll_interval);
+  loop {
+    if let Err(e) = drain_slot(pg_client, publisher, slot, publication, shutdown).await {
+      error!("{:?} -> {:?} could not drain the slot {:?}: {}", binding.pg_channel, binding.amqp_entity, slot, e);
+    }
+    if !wait_for_wake_up(notifications, poll_interval).await {
+      return;
+    }
+  }
+}
+
+async fn drain_slot(pg_client: &Client, publisher: &mut Publisher, slot: &str, publication: &str, shutdown: &Shutdown) -> Result<(), tokio_postgres::Error>{
+  let create_slot_command = "SELECT pg_create_logical_replication_slot($1, 'pgoutput')
+                             WHERE NOT EXISTS (SELECT 1 FROM pg_replication_slots WHERE slot_name = $1)";
+  pg_client.execute(create_slot_command, &[&slot]).await?;
+  let peek_command = "SELECT lsn::text, data FROM pg_logical_slot_peek_binary_changes($1, NULL, $2, 'proto_version', '1', 'publication_names', $3)";
+  loop {
+    let rows = pg_client.query(peek_command, &[&slot, &CDC_BATCH_SIZE, &publication]).await?;
+    let mut relations = HashMap::new();
+    let mut committed_lsn: Option<String> = None;
+    let mut failed = false;
+    for row in &rows {
+      if shutdown.is_overdue() {
+        failed = true;
+        break;
+      }
+      match decode_pgoutput(row.get(1), &mut relations) {
+        Ok(PgOutputMessage::Change(change)) => {
+          let message = publisher.message(&change.routing_key(), &change.row, None, Properties::default());
+          match publisher.deliver(&message).await {
+            Ok(delivery) if delivery.is_handled() => {},
+            // Nacked or returned without a fallback that took it, the transaction is published again on the next drain
+            Ok(_) => {
+              failed = true;
+              break;
+            },
+            Err(e) => {
+              error!("{:?}", e);
+              failed = true;
+              break;
+            }
+          }
+        },
+        Ok(PgOutputMessage::Commit) => committed_lsn = Some(row.get(0)),
+        Ok(PgOutputMessage::Other) => {},
+        Err(e) => {
+          error!("Could not decode the change at {} in slot {:?}: {}", row.get::<_, String>(0), slot, e);
+          failed = true;
+          break;
+        }
+      }
+    }
+    if let Some(lsn) = committed_lsn {
+      pg_client.execute("SELECT pg_replication_slot_advance($1, $2::text::pg_lsn)", &[&slot, &lsn]).await?;
+    }
+    if failed || rows.is_empty() || shutdown.is_requested() {
+      return Ok(());
+    }
+  }
+}
+
+/*
+ * Decodes a message of the pgoutput logical replication protocol(version 1). The relation messages are kept since
+ * the changes only carry the relation oid, the server sends them again at the start of every decoding session.
+*/
+fn decode_pgoutput(data: &[u8], relations: &mut HashMap<u32, Relation>) -> Result<PgOutputMessage, String>{
+  let mut reader = Reader{ data, pos: 0 };
+  match reader.u8()? {
+    b'R' => {
+      let oid = reader.u32()?;
+      let _schema = reader.string()?;
+      let table = reader.string()?;
+      let _replica_identity = reader.u8()?;
+      let mut columns = Vec::new();
+      for _ in 0..reader.i16()? {
+        let _flags = reader.u8()?;
+        let name = reader.string()?;
+        let type_oid = reader.u32()?;
+        let _type_modifier = reader.u32()?;
+        columns.push((name, type_oid));
+      }
+      relations.insert(oid, Relation{ table, columns });
+      Ok(PgOutputMessage::Other)
+    },
+    b'I' => {
+      let relation = find_relation(relations, reader.u32()?)?;
+      reader.expect(b'N')?;
+      Ok(PgOutputMessage::Change(RowChange{ table: relation.table.clone(), event: "INSERT", row: reader.row(relation)? }))
+    },
+    b'U' => {
+      let relation = find_relation(relations, reader.u32()?)?;
+      // The old key or row comes first when the replica identity changed
+      let mut kind = reader.u8()?;
+      if kind == b'K' || kind == b'O' {
+        reader.row(relation)?;
+        kind = reader.u8()?;
+      }
+      if kind != b'N' {
+        return Err(format!("Unexpected tuple type {:?} in update", kind as char));
+      }
+      Ok(PgOutputMessage::Change(RowChange{ table: relation.table.clone(), event: "UPDATE", row: reader.row(relation)? }))
+    },
+    b'D' => {
+      let relation = find_relation(relations, reader.u32()?)?;
+      match reader.u8()? {
+        b'K' | b'O' => Ok(PgOutputMessage::Change(RowChange{ table: relation.table.clone(), event: "DELETE", row: reader.row(relation)? })),
+        kind => Err(format!("Unexpected tuple type {:?} in delete", kind as char))
+      }
+    },
+    b'C' => Ok(PgOutputMessage::Commit),
+    _ => Ok(PgOutputMessage::Other)
+  }
+}
+
+fn find_relation(relations: &HashMap<u32, Relation>, oid: u32) -> Result<&Relation, String>{
+  relations.get(&oid).ok_or(format!("Unknown relation {}", oid))
+}
+
+struct Reader<'a>{
+  data: &'a [u8],
+  pos: usize
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], String>{
+    let bytes = self.data.get(self.pos..self.pos + len).ok_or("Truncated message")?;
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, String>{
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn expect(&mut self, expected: u8) -> Result<(), String>{
+    match self.u8()? {
+      byte if byte == expected => Ok(()),
+      byte => Err(format!("Expected {:?}, got {:?}", expected as char, byte as char))
+    }
+  }
+
+  fn i16(&mut self) -> Result<i16, String>{
+    let bytes = self.bytes(2)?;
+    Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, String>{
+    let bytes = self.bytes(4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  fn string(&mut self) -> Result<String, String>{
+    let len = self.data[self.pos..].iter().position(|&b| b == 0).ok_or("Unterminated string")?;
+    let string = str::from_utf8(self.bytes(len)?).map_err(|e| e.to_string())?.to_string();
+    self.pos += 1;
+    Ok(string)
+  }
+
+  // Reads the tuple data as a json object, like row_to_json does
+  fn row(&mut self, relation: &Relation) -> Result<String, String>{
+    let count = self.i16()?;
+    let mut fields = Vec::new();
+    for (name, type_oid) in relation.columns.iter().take(count as usize) {
+      match self.u8()? {
+        b'n' => fields.push(format!("{}:null", json_string(name))),
+        // Unchanged toasted values aren't sent by the server
+        b'u' => {},
+        b't' => {
+          let len = self.u32()? as usize;
+          let text = str::from_utf8(self.bytes(len)?).map_err(|e| e.to_string())?;
+          fields.push(format!("{}:{}", json_string(name), json_value(*type_oid, text)));
+        },
+        kind => return Err(format!("Unexpected column type {:?}", kind as char))
+      }
+    }
+    Ok(format!("{{{}}}", fields.join(",")))
+  }
+}
+
+fn json_string(text: &str) -> String {
+  serde_json::Value::from(text).to_string()
+}
+
+fn json_value(type_oid: u32, text: &str) -> String {
+  match type_oid {
+    BOOL_OID => (text == "t").to_string(),
+    INT2_OID | INT4_OID | INT8_OID | JSON_OID | JSONB_OID => text.to_string(),
+    // NaN and Infinity aren't valid json numbers
+    FLOAT4_OID | FLOAT8_OID | NUMERIC_OID if text.parse::<f64>().map(|f| f.is_finite()).unwrap_or(false) => text.to_string(),
+    _ => json_string(text)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RELATION: &[u8] = b"R\x00\x00\x40\x09public\x00users\x00d\x00\x04\x01id\x00\x00\x00\x00\x17\xff\xff\xff\xff\x00name\x00\x00\x00\x00\x19\xff\xff\xff\xff\x00active\x00\x00\x00\x00\x10\xff\xff\xff\xff\x00meta\x00\x00\x00\x0e\xda\xff\xff\xff\xff";
+
+  #[test]
+  fn decode_pgoutput_works() {
+    let mut relations = HashMap::new();
+    assert_eq!(Ok(PgOutputMessage::Other), decode_pgoutput(RELATION, &mut relations));
+    assert_eq!(Ok(PgOutputMessage::Change(RowChange{
+      table: "users".to_string(), event: "INSERT",
+      row: r#"{"id":1,"name":"a\"b","active":true,"meta":{"x": 1}}"#.to_string()
+    })), decode_pgoutput(b"I\x00\x00\x40\x09N\x00\x04t\x00\x00\x00\x011t\x00\x00\x00\x03a\"bt\x00\x00\x00\x01tt\x00\x00\x00\x08{\"x\": 1}", &mut relations));
+    assert_eq!(Ok(PgOutputMessage::Change(RowChange{
+      table: "users".to_string(), event: "UPDATE",
+      row: r#"{"id":1,"name":null,"active":false}"#.to_string()
+    })), decode_pgoutput(b"U\x00\x00\x40\x09K\x00\x04t\x00\x00\x00\x012nnnN\x00\x04t\x00\x00\x00\x011nt\x00\x00\x00\x01fu", &mut relations));
+    assert_eq!(Ok(PgOutputMessage::Change(RowChange{
+      table: "users".to_string(), event: "DELETE",
+      row: r#"{"id":1,"name":null,"active":null,"meta":null}"#.to_string()
+    })), decode_pgoutput(b"D\x00\x00\x40\x09K\x00\x04t\x00\x00\x00\x011nnn", &mut relations));
+    assert_eq!(Ok(PgOutputMessage::Commit), decode_pgoutput(b"C\x00\x00\x00\x00\x00\x01\x53\x85\xf8", &mut relations));
+  }
+
+  #[test]
+  fn decode_pgoutput_fails_on_unknown_relation_or_truncated_message() {
+    let mut relations = HashMap::new();
+    assert!(decode_pgoutput(b"I\x00\x00\x40\x09N\x00\x01t\x00\x00\x00\x011", &mut relations).is_err());
+    assert!(decode_pgoutput(RELATION, &mut relations).is_ok());
+    assert!(decode_pgoutput(b"I\x00\x00\x40\x09N\x00\x01t\x00\x00\x00\x05ab", &mut relations).is_err());
+  }
+
+  #[test]
+  fn routing_key_works() {
+    assert_eq!("row_change.table-users.event-INSERT",
+               RowChange{ table: "users".to_string(), event: "INSERT", row: "{}".to_string() }.routing_key());
+  }
+}
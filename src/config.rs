@@ -0,0 +1,544 @@
+use serde::Deserialize;
+use std::fs;
+use std::time::Duration;
+use toml::Value;
+
+use super::error::BridgeError;
+use super::consumer::{Consumer, ConsumerOptions, OnError, check_consumers};
+use super::relay::{Relay, RelayOptions, check_relays};
+use super::rpc::{Rpc, RpcOptions, check_rpcs};
+use super::spool::Overflow;
+use super::routing::{Field, Rule};
+use super::topology::{Kind, Topology};
+use super::{Binding, BindingOptions, FailurePolicy, NodeOrder, PayloadFormat, Type, Properties, check_bindings};
+
+/*
+ * Config file, every setting can also be given by its environment variable which takes precedence, e.g.
+ *
+ *  postgresql_uri = "postgres://postgres@localhost?sslmode=verify-full"
+ *  postgresql_ssl_root_cert = """
+ *  -----BEGIN CERTIFICATE-----
+ *  ...
+ *  """
+ *  amqp_uri = "amqp://node1//,amqp://node2//"
+ *  amqp_uri_order = "shuffled"
+ *  delivery_mode = "PERSISTENT"
+ *  shutdown_timeout = 30
+ *  spool_dir = "/var/spool/pg-amqp-bridge"
+ *  spool_max_bytes = 104857600
+ *  spool_overflow = "drop_oldest"
+ *
+ *  [[bindings]]
+ *  pg_channel = "billing"
+ *  amqp_entity = "billing_exchange"
+ *  exchange_kind = "topic"
+ *  routing_key = "billing.default"
+ *  routing_key_prefix = "tenant1."
+ *  format = "json"
+ *  confirm = true
+ *  retries = 5
+ *  on_failure = "retry"
+ *
+ *  [bindings.properties]
+ *  content_type = "application/json"
+ *
+ *  [[bindings.rules]]
+ *  when = "/tenant"
+ *  routing_key = "tenant.{value}.events"
+ *
+ *  [[consumers]]
+ *  queue = "payments"
+ *  function = "app.handle_payment"
+ *  prefetch = 10
+ *  on_error = "dead_letter"
+ *
+ *  [[relays]]
+ *  amqp_entity = "events"
+ *  pg_channel = "app_events"
+ *  entity_type = "exchange"
+ *  binding_key = "orders.#"
+ *
+ *  [[rpc]]
+ *  reply_queue = "replies"
+ *  table = "rabbitmq.rpc_replies"
+ *  notify = "rpc_replies"
+ *  timeout = 30
+ *
+ *  [[topology.exchanges]]
+ *  name = "billing_exchange"
+ *  kind = "topic"
+ *  durable = true
+ *
+ *  [[topology.queues]]
+ *  name = "invoices"
+ *  durable = true
+ *  dead_letter_exchange = "billing_dlx"
+ *  message_ttl = 60000
+ *
+ *  [[topology.bindings]]
+ *  source = "billing_exchange"
+ *  destination = "invoices"
+ *  routing_key = "tenant1.#"
+*/
+#[derive(Debug, Default)]
+pub struct ConfigFile{
+  pub postgresql_uri: Option<String>,
+  pub postgresql_ssl_root_cert: Option<String>,
+  pub postgresql_ssl_cert: Option<String>,
+  pub postgresql_ssl_key: Option<String>,
+  pub amqp_uri: Option<String>,
+  pub amqp_uri_order: Option<NodeOrder>,
+  pub amqp_ssl_root_cert: Option<String>,
+  pub amqp_ssl_cert: Option<String>,
+  pub amqp_ssl_key: Option<String>,
+  pub amqp_ssl_server_name: Option<String>,
+  pub amqp_ssl_verify: Option<bool>,
+  pub delivery_mode: Option<u8>,
+  pub shutdown_timeout: Option<u64>,
+  pub spool_dir: Option<String>,
+  pub spool_max_bytes: Option<u64>,
+  pub spool_overflow: Option<Overflow>,
+  pub bindings: Vec<Binding>,
+  pub consumers: Vec<Consumer>,
+  pub relays: Vec<Relay>,
+  pub rpcs: Vec<Rpc>,
+  pub topology: Option<Topology>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfigFile{
+  postgresql_uri: Option<String>,
+  postgresql_ssl_root_cert: Option<String>,
+  postgresql_ssl_cert: Option<String>,
+  postgresql_ssl_key: Option<String>,
+  amqp_uri: Option<String>,
+  amqp_uri_order: Option<String>,
+  amqp_ssl_root_cert: Option<String>,
+  amqp_ssl_cert: Option<String>,
+  amqp_ssl_key: Option<String>,
+  amqp_ssl_server_name: Option<String>,
+  amqp_ssl_verify: Option<bool>,
+  delivery_mode: Option<String>,
+  shutdown_timeout: Option<u64>,
+  spool_dir: Option<String>,
+  spool_max_bytes: Option<u64>,
+  spool_overflow: Option<String>,
+  #[serde(default)]
+  bindings: Vec<RawBinding>,
+  #[serde(default)]
+  consumers: Vec<RawConsumer>,
+  #[serde(default)]
+  relays: Vec<RawRelay>,
+  #[serde(default)]
+  rpc: Vec<RawRpc>,
+  topology: Option<Topology>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBinding{
+  pg_channel: String,
+  amqp_entity: String,
+  entity_type: Option<Type>,
+  exchange_kind: Option<Kind>,
+  delivery_mode: Option<String>,
+  routing_key: Option<String>,
+  routing_key_prefix: Option<String>,
+  format: Option<PayloadFormat>,
+  #[serde(default)]
+  properties: toml::value::Table,
+  #[serde(default)]
+  confirm: bool,
+  retries: Option<u32>,
+  fallback: Option<String>,
+  fallback_table: Option<String>,
+  outbox: Option<String>,
+  payload_table: Option<String>,
+  slot: Option<String>,
+  publication: Option<String>,
+  poll_interval: Option<u64>,
+  on_failure: Option<FailurePolicy>,
+  #[serde(default)]
+  rules: Vec<RawRule>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConsumer{
+  queue: String,
+  function: String,
+  prefetch: Option<u16>,
+  on_error: Option<OnError>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRelay{
+  amqp_entity: String,
+  pg_channel: String,
+  entity_type: Option<Type>,
+  binding_key: Option<String>,
+  prefetch: Option<u16>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRpc{
+  reply_queue: String,
+  table: String,
+  notify: Option<String>,
+  // Seconds
+  timeout: Option<u64>,
+  prefetch: Option<u16>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule{
+  when: Option<String>,
+  equals: Option<String>,
+  exchange: Option<String>,
+  routing_key: Option<String>
+}
+
+pub fn read_config_file(path: &str) -> Result<ConfigFile, BridgeError> {
+  let contents = fs::read_to_string(path).map_err(|e| BridgeError::Config(format!("Something went wrong reading {}: {}", path, e)))?;
+  parse_config_file(&contents)
+}
+
+// Same as the DELIVERY_MODE environment variable
+pub fn parse_delivery_mode(delivery_mode: &str) -> Option<u8> {
+  match delivery_mode {
+    "NON-PERSISTENT" => Some(1),
+    "PERSISTENT" => Some(2),
+    _ => None
+  }
+}
+
+// Same as the AMQP_URI_ORDER environment variable
+pub fn parse_node_order(node_order: &str) -> Option<NodeOrder> {
+  match node_order {
+    "ordered" => Some(NodeOrder::Ordered),
+    "shuffled" => Some(NodeOrder::Shuffled),
+    _ => None
+  }
+}
+
+// Same as the SPOOL_OVERFLOW environment variable
+pub fn parse_spool_overflow(overflow: &str) -> Option<Overflow> {
+  match overflow {
+    "reject" => Some(Overflow::Reject),
+    "drop_oldest" => Some(Overflow::DropOldest),
+    _ => None
+  }
+}
+
+// The nodes of a cluster are separated by commas
+pub fn parse_amqp_uris(amqp_uri: &str) -> Vec<String> {
+  amqp_uri.split(',').map(|uri| uri.trim().to_string()).filter(|uri| !uri.is_empty()).collect()
+}
+
+fn parse_config_file(contents: &str) -> Result<ConfigFile, BridgeError> {
+  let raw: RawConfigFile = toml::from_str(contents).map_err(|e| BridgeError::Config(e.to_string()))?;
+  let mut bindings = Vec::new();
+  for raw_binding in raw.bindings {
+    bindings.push(binding(raw_binding)?);
+  }
+  let defaults = ConsumerOptions::default();
+  let consumers = raw.consumers.into_iter().map(|raw| Consumer{
+    queue: raw.queue.trim().to_string(),
+    function: raw.function.trim().to_string(),
+    options: ConsumerOptions{ prefetch: raw.prefetch.unwrap_or(defaults.prefetch), on_error: raw.on_error.unwrap_or(defaults.on_error) }
+  }.finish()).collect::<Result<Vec<Consumer>, BridgeError>>()?;
+  let defaults = RelayOptions::default();
+  let relays = raw.relays.into_iter().map(|raw| Relay{
+    amqp_entity: raw.amqp_entity.trim().to_string(),
+    pg_channel: raw.pg_channel.trim().to_string(),
+    options: RelayOptions{
+      entity_type: raw.entity_type,
+      binding_key: raw.binding_key.unwrap_or(defaults.binding_key.clone()),
+      prefetch: raw.prefetch.unwrap_or(defaults.prefetch)
+    }
+  }.finish()).collect::<Result<Vec<Relay>, BridgeError>>()?;
+  let defaults = RpcOptions::default();
+  let rpcs = raw.rpc.into_iter().map(|raw| Rpc{
+    reply_queue: raw.reply_queue.trim().to_string(),
+    table: raw.table.trim().to_string(),
+    options: RpcOptions{
+      notify: raw.notify,
+      timeout: raw.timeout.map_or(defaults.timeout, Duration::from_secs),
+      prefetch: raw.prefetch.unwrap_or(defaults.prefetch)
+    }
+  }.finish()).collect::<Result<Vec<Rpc>, BridgeError>>()?;
+  if let Some(ref topology) = raw.topology {
+    topology.check()?;
+  }
+  Ok(ConfigFile{
+    postgresql_uri: raw.postgresql_uri,
+    postgresql_ssl_root_cert: raw.postgresql_ssl_root_cert,
+    postgresql_ssl_cert: raw.postgresql_ssl_cert,
+    postgresql_ssl_key: raw.postgresql_ssl_key,
+    amqp_uri: raw.amqp_uri,
+    amqp_uri_order: raw.amqp_uri_order.map(|order| node_order(&order)).transpose()?,
+    amqp_ssl_root_cert: raw.amqp_ssl_root_cert,
+    amqp_ssl_cert: raw.amqp_ssl_cert,
+    amqp_ssl_key: raw.amqp_ssl_key,
+    amqp_ssl_server_name: raw.amqp_ssl_server_name,
+    amqp_ssl_verify: raw.amqp_ssl_verify,
+    delivery_mode: raw.delivery_mode.map(|d| delivery_mode(&d)).transpose()?,
+    shutdown_timeout: raw.shutdown_timeout,
+    spool_dir: raw.spool_dir,
+    spool_max_bytes: raw.spool_max_bytes,
+    spool_overflow: raw.spool_overflow.map(|overflow| spool_overflow(&overflow)).transpose()?,
+    bindings: if bindings.is_empty() { bindings } else { check_bindings(bindings)? },
+    consumers: check_consumers(consumers)?,
+    relays: check_relays(relays)?,
+    rpcs: check_rpcs(rpcs)?,
+    topology: raw.topology
+  })
+}
+
+fn binding(raw: RawBinding) -> Result<Binding, BridgeError> {
+  if raw.pg_channel.trim().is_empty() || raw.amqp_entity.trim().is_empty() {
+    return Err(BridgeError::Config("Every binding must have a pg_channel and an amqp_entity".to_string()));
+  }
+  let defaults = BindingOptions::default();
+  let mut properties = Properties::default();
+  for (name, value) in raw.properties {
+    match value {
+      Value::String(value) => properties.set(&name, &value).map_err(BridgeError::Config)?,
+      Value::Integer(value) => properties.set(&name, &value.to_string()).map_err(BridgeError::Config)?,
+      _ => return Err(BridgeError::Config(format!("Property {} must be a string or an integer", name)))
+    }
+  }
+  Ok(Binding{
+    pg_channel: raw.pg_channel.trim().to_string(),
+    amqp_entity: raw.amqp_entity.trim().to_string(),
+    options: BindingOptions{
+      entity_type: raw.entity_type,
+      exchange_kind: raw.exchange_kind,
+      delivery_mode: raw.delivery_mode.map(|d| delivery_mode(&d)).transpose()?,
+      routing_key: raw.routing_key,
+      routing_key_prefix: raw.routing_key_prefix,
+      rules: raw.rules.into_iter().map(rule).collect::<Result<Vec<Rule>, BridgeError>>()?,
+      format: raw.format.unwrap_or(defaults.format),
+      properties,
+      confirm: raw.confirm,
+      retries: raw.retries.unwrap_or(defaults.retries),
+      fallback: raw.fallback,
+      fallback_table: raw.fallback_table,
+      outbox: raw.outbox,
+      payload_table: raw.payload_table,
+      slot: raw.slot,
+      publication: raw.publication,
+      poll_interval: raw.poll_interval.unwrap_or(defaults.poll_interval),
+      on_failure: raw.on_failure.unwrap_or(defaults.on_failure)
+    }.finish()?
+  })
+}
+
+fn rule(raw: RawRule) -> Result<Rule, BridgeError> {
+  Ok(Rule{
+    when: raw.when.map(|when| Field::parse(&when)).transpose().map_err(BridgeError::Config)?,
+    equals: raw.equals,
+    exchange: raw.exchange,
+    routing_key: raw.routing_key
+  })
+}
+
+fn node_order(node_order: &str) -> Result<NodeOrder, BridgeError> {
+  parse_node_order(node_order)
+    .ok_or_else(|| BridgeError::Config(format!("amqp_uri_order can only be ordered or shuffled, got {:?}", node_order)))
+}
+
+fn spool_overflow(overflow: &str) -> Result<Overflow, BridgeError> {
+  parse_spool_overflow(overflow)
+    .ok_or_else(|| BridgeError::Config(format!("spool_overflow can only be reject or drop_oldest, got {:?}", overflow)))
+}
+
+fn delivery_mode(delivery_mode: &str) -> Result<u8, BridgeError> {
+  parse_delivery_mode(delivery_mode)
+    .ok_or_else(|| BridgeError::Config(format!("delivery_mode can only be PERSISTENT or NON-PERSISTENT, got {:?}", delivery_mode)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeMap;
+  use super::super::topology::{Argument, DestinationType, Exchange, Queue, TopologyBinding};
+
+  #[test]
+  fn parse_config_file_works() {
+    let config = parse_config_file(r#"
+      postgresql_uri = "postgres://postgres@localhost"
+      postgresql_ssl_root_cert = "root certificate"
+      amqp_uri = "amqps://localhost//?auth_mechanism=external"
+      amqp_uri_order = "shuffled"
+      amqp_ssl_server_name = "rabbitmq.local"
+      amqp_ssl_verify = false
+      delivery_mode = "PERSISTENT"
+      shutdown_timeout = 30
+      spool_dir = "/var/spool/bridge"
+      spool_overflow = "drop_oldest"
+
+      [[bindings]]
+      pg_channel = "pgchannel1"
+      amqp_entity = "exchange1"
+      entity_type = "exchange"
+      exchange_kind = "topic"
+      delivery_mode = "NON-PERSISTENT"
+      routing_key = "default_key"
+      routing_key_prefix = "tenant1."
+      format = "json"
+      confirm = true
+      retries = 5
+      fallback_table = "bridge.failed"
+
+      [bindings.properties]
+      content_type = "application/json"
+      priority = 5
+
+      [[bindings]]
+      pg_channel = "pgchannel2"
+      amqp_entity = "queue2"
+      slot = "bridge_slot"
+
+      [[bindings]]
+      pg_channel = "pgchannel1"
+      amqp_entity = "audit_queue"
+      on_failure = "skip"
+
+      [[bindings.rules]]
+      when = "header:X-Tenant"
+      equals = "acme"
+      exchange = "acme_events"
+
+      [[bindings.rules]]
+      routing_key = "{pg_channel}.{routing_key}"
+
+      [[consumers]]
+      queue = "payments"
+      function = "app.handle_payment"
+      on_error = "requeue"
+
+      [[relays]]
+      amqp_entity = "events"
+      pg_channel = "app_events"
+      binding_key = "orders.#"
+
+      [[rpc]]
+      reply_queue = "replies"
+      table = "rabbitmq.rpc_replies"
+      notify = "rpc_replies"
+      timeout = 5
+    "#).unwrap();
+    assert_eq!(Some("postgres://postgres@localhost".to_string()), config.postgresql_uri);
+    assert_eq!(Some("root certificate".to_string()), config.postgresql_ssl_root_cert);
+    assert_eq!(None, config.postgresql_ssl_cert);
+    assert_eq!(Some("amqps://localhost//?auth_mechanism=external".to_string()), config.amqp_uri);
+    assert_eq!(Some(NodeOrder::Shuffled), config.amqp_uri_order);
+    assert_eq!(Some("rabbitmq.local".to_string()), config.amqp_ssl_server_name);
+    assert_eq!(Some(false), config.amqp_ssl_verify);
+    assert_eq!(Some(2), config.delivery_mode);
+    assert_eq!(Some(30), config.shutdown_timeout);
+    assert_eq!(Some("/var/spool/bridge".to_string()), config.spool_dir);
+    assert_eq!(None, config.spool_max_bytes);
+    assert_eq!(Some(Overflow::DropOldest), config.spool_overflow);
+    assert_eq!(vec![
+      Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "audit_queue".to_string(),
+              options: BindingOptions{on_failure: FailurePolicy::Skip, rules: vec![
+                Rule{when: Some(Field::Header("X-Tenant".to_string())), equals: Some("acme".to_string()), exchange: Some("acme_events".to_string()), routing_key: None},
+                Rule{routing_key: Some("{pg_channel}.{routing_key}".to_string()), ..Rule::default()}
+              ], ..BindingOptions::default()}},
+      Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(),
+              options: BindingOptions{entity_type: Some(Type::Exchange), exchange_kind: Some(Kind::Topic), delivery_mode: Some(1), routing_key: Some("default_key".to_string()),
+                                      routing_key_prefix: Some("tenant1.".to_string()),
+                                      format: PayloadFormat::Json, confirm: true, retries: 5, fallback_table: Some("bridge.failed".to_string()),
+                                      properties: Properties{content_type: Some("application/json".to_string()), priority: Some(5), ..Properties::default()},
+                                      ..BindingOptions::default()}},
+      Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "queue2".to_string(),
+              options: BindingOptions{confirm: true, slot: Some("bridge_slot".to_string()),
+                                      properties: Properties{content_type: Some("application/json".to_string()), ..Properties::default()},
+                                      ..BindingOptions::default()}}
+    ], config.bindings);
+    assert_eq!(vec![Consumer{queue: "payments".to_string(), function: "app.handle_payment".to_string(),
+                             options: ConsumerOptions{prefetch: 10, on_error: OnError::Requeue}}], config.consumers);
+    assert_eq!(vec![Relay{amqp_entity: "events".to_string(), pg_channel: "app_events".to_string(),
+                          options: RelayOptions{entity_type: None, binding_key: "orders.#".to_string(), prefetch: 10}}], config.relays);
+    assert_eq!(vec![Rpc{reply_queue: "replies".to_string(), table: "rabbitmq.rpc_replies".to_string(),
+                        options: RpcOptions{notify: Some("rpc_replies".to_string()), timeout: Duration::from_secs(5), prefetch: 10}}], config.rpcs);
+    assert!(parse_config_file("").unwrap().bindings.is_empty());
+    assert_eq!(None, config.topology);
+  }
+
+  #[test]
+  fn parse_config_file_topology_works() {
+    let config = parse_config_file(r#"
+      [[topology.exchanges]]
+      name = "billing"
+      kind = "topic"
+      durable = true
+
+      [topology.exchanges.arguments]
+      alternate-exchange = "unrouted"
+
+      [[topology.queues]]
+      name = "invoices"
+      durable = true
+      dead_letter_exchange = "billing_dlx"
+      message_ttl = 60000
+      max_length = 1000
+
+      [[topology.bindings]]
+      source = "billing"
+      destination = "invoices"
+      routing_key = "invoice.#"
+
+      [[topology.bindings]]
+      source = "billing"
+      destination = "audit"
+      destination_type = "exchange"
+    "#).unwrap();
+    assert_eq!(Some(Topology{
+      exchanges: vec![Exchange{name: "billing".to_string(), kind: Kind::Topic, durable: true, auto_delete: false,
+                               arguments: btreemap!{"alternate-exchange".to_string() => Argument::String("unrouted".to_string())}}],
+      queues: vec![Queue{name: "invoices".to_string(), durable: true, dead_letter_exchange: Some("billing_dlx".to_string()),
+                         message_ttl: Some(60000), max_length: Some(1000), ..Queue::default()}],
+      bindings: vec![
+        TopologyBinding{source: "billing".to_string(), destination: "invoices".to_string(), destination_type: DestinationType::Queue,
+                        routing_key: "invoice.#".to_string(), arguments: BTreeMap::new()},
+        TopologyBinding{source: "billing".to_string(), destination: "audit".to_string(), destination_type: DestinationType::Exchange,
+                        routing_key: "".to_string(), arguments: BTreeMap::new()}
+      ]
+    }), config.topology);
+  }
+
+  #[test]
+  fn parse_amqp_uris_works() {
+    assert_eq!(vec!["amqp://localhost//"], parse_amqp_uris("amqp://localhost//"));
+    assert_eq!(vec!["amqp://node1//", "amqps://node2//"], parse_amqp_uris(" amqp://node1//, amqps://node2//,"));
+    assert!(parse_amqp_uris("").is_empty());
+  }
+
+  #[test]
+  fn parse_config_file_fails_on_invalid_config() {
+    assert!(parse_config_file("[[bindings]]\npg_channel = \"pgchannel1\"\namqp_entity = \"queue1\"\n[[bindings.rules]]\nwhen = \"tenant\"\nexchange = \"events\"").is_err());
+    assert!(parse_config_file("[[bindings]]\npg_channel = \"pgchannel1\"\namqp_entity = \"queue1\"\n[[bindings.rules]]\nwhen = \"/tenant\"").is_err());
+    assert!(parse_config_file("[[consumers]]\nqueue = \"payments\"\nfunction = \"app.handle_payment()\"").is_err());
+    assert!(parse_config_file("spool_overflow = \"block\"").is_err());
+    assert!(parse_config_file("[[rpc]]\nreply_queue = \"replies\"\ntable = \"rpc_replies\"\ntimeout = 0").is_err());
+    assert!(parse_config_file("[[relays]]\namqp_entity = \"alerts\"\npg_channel = \"alerts\"\nentity_type = \"queue\"\nbinding_key = \"high\"").is_err());
+    assert!(parse_config_file("[[consumers]]\nqueue = \"payments\"\nfunction = \"app.handle_payment\"\non_error = \"drop\"").is_err());
+    assert!(parse_config_file("[[topology.exchanges]]\nname = \"billing\"\nkind = \"broadcast\"").is_err());
+    assert!(parse_config_file("[[topology.queues]]\nname = \"\"").is_err());
+    assert!(parse_config_file("[[topology.queues]]\nname = \"invoices\"\nttl = 60000").is_err());
+    assert!(parse_config_file("delivery_mode = \"SOMETIMES\"").is_err());
+    assert!(parse_config_file("amqp_uri_order = \"random\"").is_err());
+    assert!(parse_config_file("bridge_channels = \"pgchannel1:queue1\"").is_err());
+    assert!(parse_config_file("[[bindings]]\npg_channel = \"pgchannel1\"").is_err());
+    assert!(parse_config_file("[[bindings]]\npg_channel = \"pgchannel1\"\namqp_entity = \"queue1\"\nentity_type = \"topic\"").is_err());
+    assert!(parse_config_file("[[bindings]]\npg_channel = \"pgchannel1\"\namqp_entity = \"queue1\"\nentity_type = \"queue\"\nexchange_kind = \"topic\"").is_err());
+    assert!(parse_config_file("[[bindings]]\npg_channel = \"pgchannel1\"\namqp_entity = \"queue1\"\n[bindings.properties]\npriority = \"high\"").is_err());
+  }
+}
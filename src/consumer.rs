@@ -0,0 +1,414 @@
+use lapin::Channel;
+use lapin::message::{Delivery, DeliveryResult};
+use lapin::options::{BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_postgres::{Client, Statement};
+
+use super::{OPTIONS_SEPARATOR, OPTION_NAME_VALUE_SEPARATOR, OPTION_SEPARATOR, Type};
+use super::amqp::{AmqpConnection, AmqpNode, check_amqp_entity_type, close_channel};
+use super::error::BridgeError;
+use super::relay::{Relay, notification_payload};
+use super::rpc::{Calls, REPLIED, Reply, Rpc};
+
+const CONSUMER_SEPARATOR: char = ':';
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// What happens to a message whose SQL function fails
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+  // Back to the queue, to be delivered again
+  Requeue,
+  // Rejected, the broker sends it to the dead letter exchange of the queue if it has one or drops it
+  DeadLetter
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct ConsumerOptions{
+  // Messages delivered to the bridge before it acks them
+  pub prefetch: u16,
+  pub on_error: OnError
+}
+
+impl Default for ConsumerOptions {
+  fn default() -> ConsumerOptions {
+    ConsumerOptions{ prefetch: 10, on_error: OnError::DeadLetter }
+  }
+}
+
+/*
+ * Consumes an AMQP queue into PostgreSQL, every message is given to the SQL function as its routing key, headers
+ * (jsonb) and body, e.g. app.handle_message(routing_key text, headers jsonb, body text).
+*/
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Consumer{
+  pub queue: String,
+  pub function: String,
+  pub options: ConsumerOptions
+}
+
+impl Consumer {
+  pub fn new(queue: &str, function: &str) -> Consumer {
+    Consumer{ queue: queue.to_string(), function: function.to_string(), options: ConsumerOptions::default() }
+  }
+
+  // The function name goes into the SQL statement, so it can only be a plain, optionally schema qualified, name
+  pub(crate) fn finish(self) -> Result<Consumer, BridgeError> {
+    if self.queue.trim().is_empty() {
+      return Err(BridgeError::Config(format!("Consumers need a queue, got {:?}", self.queue)));
+    }
+    if !is_qualified_name(&self.function) {
+      return Err(BridgeError::Config(format!("The function of a consumer must be a name like app.handle_message, got {:?}", self.function)));
+    }
+    if self.options.prefetch == 0 {
+      return Err(BridgeError::Config(format!("The prefetch of the {:?} consumer must be at least 1", self.queue)));
+    }
+    Ok(self)
+  }
+}
+
+// A plain name, optionally schema qualified, e.g. app.handle_message
+pub(crate) fn is_qualified_name(name: &str) -> bool {
+  let valid_name = |name: &str| name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+                                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+  let parts: Vec<&str> = name.split('.').collect();
+  parts.len() <= 2 && parts.iter().all(|part| valid_name(part))
+}
+
+// e.g. BRIDGE_CONSUMERS="events:app.handle_event?prefetch=1,commands:app.handle_command?on_error=requeue"
+pub fn parse_bridge_consumers(bridge_consumers: &str) -> Result<Vec<Consumer>, BridgeError> {
+  let mut consumers = Vec::new();
+  for consumer in bridge_consumers.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    let queue_and_rest: Vec<&str> = consumer.splitn(2, CONSUMER_SEPARATOR).collect();
+    let function_and_options: Vec<&str> = queue_and_rest.get(1).unwrap_or(&"").splitn(2, OPTIONS_SEPARATOR).collect();
+    consumers.push(Consumer{
+      queue: queue_and_rest[0].trim().to_string(),
+      function: function_and_options[0].trim().to_string(),
+      options: parse_consumer_options(function_and_options.get(1).unwrap_or(&""))?
+    }.finish()?);
+  }
+  check_consumers(consumers)
+}
+
+pub(crate) fn check_consumers(mut consumers: Vec<Consumer>) -> Result<Vec<Consumer>, BridgeError> {
+  consumers.sort();
+  if consumers.windows(2).any(|pair| pair[0].queue == pair[1].queue) {
+    return Err(BridgeError::Config("Cannot have several consumers of the same queue".to_string()));
+  }
+  Ok(consumers)
+}
+
+fn parse_consumer_options(options: &str) -> Result<ConsumerOptions, BridgeError> {
+  let mut consumer_options = ConsumerOptions::default();
+  for option in options.split(OPTION_SEPARATOR).map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    let name_value: Vec<&str> = option.splitn(2, OPTION_NAME_VALUE_SEPARATOR).map(|x| x.trim()).collect();
+    match name_value[..] {
+      ["prefetch", prefetch] =>
+        consumer_options.prefetch = prefetch.parse().map_err(|_| BridgeError::Config(format!("Consumer option prefetch must be a number, got \"{}\"", prefetch)))?,
+      ["on_error", "requeue"] => consumer_options.on_error = OnError::Requeue,
+      ["on_error", "dead_letter"] => consumer_options.on_error = OnError::DeadLetter,
+      _ => return Err(BridgeError::Config(format!("Unknown consumer option \"{}\"", option)))
+    }
+  }
+  Ok(consumer_options)
+}
+
+// What the bridge consumes from the broker: messages given to a SQL function, relayed as notifications or RPC replies
+#[derive(Debug, Clone)]
+pub(crate) enum Endpoint {
+  Consumer(Consumer),
+  Relay(Relay),
+  Rpc(Rpc, Arc<Calls>)
+}
+
+impl Endpoint {
+  fn name(&self) -> String {
+    match self {
+      Endpoint::Consumer(consumer) => format!("{:?} -> {}", consumer.queue, consumer.function),
+      Endpoint::Relay(relay) => format!("{:?} -> NOTIFY {}", relay.amqp_entity, relay.pg_channel),
+      Endpoint::Rpc(rpc, _) => format!("{:?} -> {}", rpc.reply_queue, rpc.table)
+    }
+  }
+
+  fn statement(&self) -> String {
+    match self {
+      Endpoint::Consumer(consumer) => format!("SELECT {}($1, $2::text::jsonb, $3)", consumer.function),
+      Endpoint::Relay(_) => "SELECT pg_notify($1, $2)".to_string(),
+      Endpoint::Rpc(rpc, _) => rpc.statement()
+    }
+  }
+
+  fn prefetch(&self) -> u16 {
+    match self {
+      Endpoint::Consumer(consumer) => consumer.options.prefetch,
+      Endpoint::Relay(relay) => relay.options.prefetch,
+      Endpoint::Rpc(rpc, _) => rpc.options.prefetch
+    }
+  }
+
+  /*
+   * A notification that can't be sent, e.g. one over the 8000 bytes limit, won't be sent by trying again, neither
+   * will a reply without a correlation id be written.
+  */
+  fn on_error(&self) -> OnError {
+    match self {
+      Endpoint::Consumer(consumer) => consumer.options.on_error,
+      Endpoint::Relay(_) | Endpoint::Rpc(..) => OnError::DeadLetter
+    }
+  }
+
+  async fn queue(&self, node: &AmqpNode, channel: &Channel) -> Result<String, BridgeError> {
+    match self {
+      Endpoint::Consumer(consumer) => {
+        check_amqp_entity_type(&node.connection, &consumer.queue, &Type::Queue, None).await?;
+        Ok(consumer.queue.clone())
+      },
+      Endpoint::Relay(relay) => relay.declare_queue(&node.connection, channel).await,
+      Endpoint::Rpc(rpc, _) => {
+        check_amqp_entity_type(&node.connection, &rpc.reply_queue, &Type::Queue, None).await?;
+        Ok(rpc.reply_queue.clone())
+      }
+    }
+  }
+
+  async fn execute(&self, pg_client: &Client, statement: &Statement, delivery: &Delivery) -> Result<u64, tokio_postgres::Error> {
+    let body = String::from_utf8_lossy(&delivery.data);
+    match self {
+      Endpoint::Consumer(_) =>
+        pg_client.execute(statement, &[&delivery.routing_key.as_str(), &json_headers(delivery).to_string(), &body.as_ref()]).await,
+      Endpoint::Relay(relay) =>
+        pg_client.execute(statement, &[&relay.pg_channel, &notification_payload(delivery.routing_key.as_str(), &body)]).await,
+      Endpoint::Rpc(rpc, calls) => {
+        let correlation_id = delivery.properties.correlation_id().as_ref().map(|id| id.to_string());
+        let reply = Reply{
+          correlation_id: correlation_id.as_deref(), status: REPLIED, routing_key: Some(delivery.routing_key.as_str()),
+          headers: Some(json_headers(delivery).to_string()), body: Some(&body)
+        };
+        let recorded = rpc.record(pg_client, statement, &reply).await;
+        if let (Ok(_), Some(correlation_id)) = (&recorded, &correlation_id) {
+          calls.replied(&rpc.reply_queue, correlation_id);
+        }
+        recorded
+      }
+    }
+  }
+
+  // The calls that got no reply in time are written as timed out
+  async fn expire(&self, pg_client: &Client, statement: &Statement) {
+    if let Endpoint::Rpc(rpc, calls) = self {
+      for correlation_id in calls.expired(&rpc.reply_queue) {
+        warn!("{} call {:?} timed out", self.name(), correlation_id);
+        if let Err(e) = rpc.record(pg_client, statement, &Reply::timed_out(&correlation_id)).await {
+          error!("{} could not write the timeout of the call {:?}: {}", self.name(), correlation_id, e);
+        }
+      }
+    }
+  }
+}
+
+// The consuming channel of a queue, the deliveries are taken from the lapin delegate in order
+pub struct Subscription{
+  node: AmqpNode,
+  channel: Channel,
+  tag: String,
+  statement: Statement,
+  deliveries: UnboundedReceiver<DeliveryResult>
+}
+
+impl Subscription {
+  /*
+   * Fails when the queue, the exchange or the function don't exist. On a lost connection the next node is taken,
+   * on a lost channel the connection is kept.
+  */
+  pub(crate) async fn new(amqp: &AmqpConnection, pg_client: &Client, endpoint: &Endpoint, lost: Option<&AmqpNode>) -> Result<Subscription, BridgeError> {
+    let statement = pg_client.prepare(&endpoint.statement()).await?;
+    let node = match lost {
+      Some(lost) if !lost.connection.status().connected() => amqp.replace(lost, true).await?,
+      _ => amqp.get().await?
+    };
+    let channel = node.connection.create_channel().await?;
+    let queue = match endpoint.queue(&node, &channel).await {
+      Ok(queue) => queue,
+      Err(e) => {
+        close_channel(&channel).await;
+        return Err(e);
+      }
+    };
+    channel.basic_qos(endpoint.prefetch(), BasicQosOptions::default()).await?;
+    let amqp_consumer = channel.basic_consume(&queue, "", BasicConsumeOptions::default(), FieldTable::default()).await?;
+    let (sender, deliveries) = mpsc::unbounded_channel();
+    amqp_consumer.set_delegate(move |delivery: DeliveryResult| {
+      let sender = sender.clone();
+      async move {
+        let _ = sender.send(delivery);
+      }
+    });
+    info!("{} consuming from AMQP server {}", endpoint.name(), node.name);
+    Ok(Subscription{ node, channel, tag: amqp_consumer.tag().to_string(), statement, deliveries })
+  }
+
+  // The deliveries that weren't acked go back to the queue
+  async fn close(self) {
+    if let Err(e) = self.channel.basic_cancel(&self.tag, BasicCancelOptions::default()).await {
+      error!("{:?}", e);
+    }
+    close_channel(&self.channel).await;
+  }
+}
+
+/*
+ * Gives the messages to the SQL function, or relays them, until the stop channel is closed or the PostgreSQL connection
+ * is lost. A message is acked once its statement returns, it runs in its own transaction so it's committed by then.
+*/
+pub(crate) async fn relay_deliveries(amqp: &AmqpConnection, pg_client: &Client, endpoint: &Endpoint, mut subscription: Subscription, stop: &mut UnboundedReceiver<()>) {
+  let mut expiry = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+  let expires = matches!(endpoint, Endpoint::Rpc(..));
+  loop {
+    tokio::select! {
+      _ = stop.recv() => break,
+      _ = expiry.tick(), if expires => endpoint.expire(pg_client, &subscription.statement).await,
+      delivery = subscription.deliveries.recv() => match delivery {
+        Some(Ok(Some(delivery))) => {
+          if let Err(e) = deliver(pg_client, endpoint, &subscription.statement, delivery).await {
+            error!("{} stopped: {}", endpoint.name(), e);
+            break;
+          }
+        },
+        lost => {
+          warn!("{} lost its AMQP channel: {:?}", endpoint.name(), lost.map(|delivery| delivery.err()));
+          subscription = match resubscribe(amqp, pg_client, endpoint, &subscription.node, stop).await {
+            Some(subscription) => subscription,
+            None => return
+          };
+        }
+      }
+    }
+  }
+  subscription.close().await;
+  println!("Stopped consuming {}", endpoint.name());
+}
+
+/*
+ * Consumes again with a backoff, like the publishers do, until it works or the stop channel is closed. Only a lost
+ * PostgreSQL connection gives up, the statement can't be prepared again without it.
+*/
+async fn resubscribe(amqp: &AmqpConnection, pg_client: &Client, endpoint: &Endpoint, lost: &AmqpNode, stop: &mut UnboundedReceiver<()>) -> Option<Subscription> {
+  let mut i = 1;
+  loop {
+    match Subscription::new(amqp, pg_client, endpoint, Some(lost)).await {
+      Ok(subscription) => return Some(subscription),
+      Err(BridgeError::Stopped) => return None,
+      Err(e) if pg_client.is_closed() => {
+        error!("{} stopped: {}", endpoint.name(), e);
+        return None;
+      },
+      Err(e) => error!("{} could not consume again: {}", endpoint.name(), e)
+    }
+    let time = Duration::from_secs(i);
+    println!("Retrying {} in {:?} seconds..", endpoint.name(), time.as_secs());
+    tokio::select! {
+      _ = stop.recv() => return None,
+      _ = tokio::time::sleep(time) => {}
+    }
+    i *= 2;
+    if i > 32 { i = 1 };
+  }
+}
+
+// Errors without a SQL state mean the statement didn't run, the message goes back to the queue and the error is returned
+async fn deliver(pg_client: &Client, endpoint: &Endpoint, statement: &Statement, delivery: Delivery) -> Result<(), BridgeError> {
+  let result = endpoint.execute(pg_client, statement, &delivery).await;
+  let (acked, failed) = match result {
+    Ok(_) => (delivery.acker.ack(BasicAckOptions::default()).await, None),
+    Err(e) => {
+      let ran = e.code().is_some();
+      let requeue = !ran || endpoint.on_error() == OnError::Requeue;
+      let e = BridgeError::Postgres(e);
+      error!("{} failed, the message is {}: {}", endpoint.name(), if requeue { "requeued" } else { "dead lettered" }, e);
+      let nacked = delivery.acker.nack(BasicNackOptions{ requeue, ..BasicNackOptions::default() }).await;
+      (nacked, if ran { None } else { Some(e) })
+    }
+  };
+  if let Err(e) = acked {
+    error!("{} could not acknowledge the message: {}", endpoint.name(), e);
+  }
+  failed.map_or(Ok(()), Err)
+}
+
+fn json_headers(delivery: &Delivery) -> Value {
+  match delivery.properties.headers() {
+    Some(headers) => json_object(headers),
+    None => Value::Object(Map::new())
+  }
+}
+
+fn json_object(headers: &FieldTable) -> Value {
+  Value::Object(headers.inner().iter().map(|(name, value)| (name.to_string(), json_value(value))).collect())
+}
+
+// The inverse of the json payload headers, the values without a json equivalent are sent as strings
+fn json_value(value: &AMQPValue) -> Value {
+  match value {
+    AMQPValue::Void => Value::Null,
+    AMQPValue::Boolean(b) => Value::Bool(*b),
+    AMQPValue::ShortShortInt(i) => Value::from(*i),
+    AMQPValue::ShortShortUInt(i) => Value::from(*i),
+    AMQPValue::ShortInt(i) => Value::from(*i),
+    AMQPValue::ShortUInt(i) => Value::from(*i),
+    AMQPValue::LongInt(i) => Value::from(*i),
+    AMQPValue::LongUInt(i) => Value::from(*i),
+    AMQPValue::LongLongInt(i) => Value::from(*i),
+    AMQPValue::Timestamp(t) => Value::from(*t),
+    AMQPValue::Float(f) => Value::from(*f),
+    AMQPValue::Double(f) => Value::from(*f),
+    AMQPValue::ShortString(s) => Value::String(s.to_string()),
+    AMQPValue::LongString(s) => Value::String(s.to_string()),
+    AMQPValue::FieldArray(values) => Value::Array(values.as_slice().iter().map(json_value).collect()),
+    AMQPValue::FieldTable(fields) => json_object(fields),
+    value => Value::String(format!("{:?}", value))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_bridge_consumers_works() {
+    assert_eq!(vec![
+      Consumer::new("commands", "handle_command"),
+      Consumer{ queue: "events".to_string(), function: "app.handle_event".to_string(),
+                options: ConsumerOptions{ prefetch: 1, on_error: OnError::Requeue } }
+    ], parse_bridge_consumers(" events : app.handle_event ? prefetch=1 & on_error=requeue, commands:handle_command,").unwrap());
+  }
+
+  #[test]
+  fn parse_bridge_consumers_fails_on_invalid_consumers() {
+    assert!(parse_bridge_consumers("events").is_err());
+    assert!(parse_bridge_consumers(":app.handle_event").is_err());
+    assert!(parse_bridge_consumers("events:app.handle_event(1)").is_err());
+    assert!(parse_bridge_consumers("events:app.handle_event; drop table app.events").is_err());
+    assert!(parse_bridge_consumers("events:db.app.handle_event").is_err());
+    assert!(parse_bridge_consumers("events:app.handle_event?prefetch=0").is_err());
+    assert!(parse_bridge_consumers("events:app.handle_event?on_error=drop").is_err());
+    assert!(parse_bridge_consumers("events:app.handle_event,events:app.other").is_err());
+  }
+
+  #[test]
+  fn json_object_works() {
+    let headers = FieldTable::from(btreemap!{
+      "X-Retries".into() => AMQPValue::LongLongInt(3),
+      "X-Tags".into() => AMQPValue::FieldArray(vec![AMQPValue::LongString("a".into()), AMQPValue::Boolean(true)].into()),
+      "X-Origin".into() => AMQPValue::FieldTable(FieldTable::from(btreemap!{ "service".into() => AMQPValue::ShortString("billing".into()) })),
+      "X-None".into() => AMQPValue::Void
+    });
+    assert_eq!(serde_json::json!({
+      "X-Retries": 3, "X-Tags": ["a", true], "X-Origin": {"service": "billing"}, "X-None": null
+    }), json_object(&headers));
+  }
+}
@@ -0,0 +1,130 @@
+use lapin::types::{AMQPValue, FieldTable};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use super::properties::Properties;
+
+#[derive(Debug, PartialEq)]
+pub struct Envelope{
+  pub routing_key: String,
+  pub headers: Option<FieldTable>,
+  pub properties: Properties,
+  pub body: String
+}
+
+/*
+ * Parses a payload in the json format, e.g.
+ * {"routing_key": "my_key", "headers": {"X-Retries": 3, "X-Tags": ["a", "b"]}, "properties": {"priority": 5}, "body": {"id": 1}}
+ * A string body is sent as is, any other json body is sent serialized.
+*/
+pub fn parse_envelope(payload: &str) -> Result<Envelope, String>{
+  let value: Value = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+  let object = value.as_object().ok_or("The payload must be a json object")?;
+  let routing_key = match object.get("routing_key") {
+    None | Some(Value::Null) => String::new(),
+    Some(Value::String(routing_key)) => routing_key.clone(),
+    Some(_) => return Err("routing_key must be a string".to_string())
+  };
+  let headers = match object.get("headers") {
+    None | Some(Value::Null) => None,
+    Some(Value::Object(headers)) => Some(field_table(headers)),
+    Some(_) => return Err("headers must be an object".to_string())
+  };
+  let mut properties = Properties::default();
+  match object.get("properties") {
+    None | Some(Value::Null) => {},
+    Some(Value::Object(values)) =>
+      for (name, value) in values {
+        match value {
+          Value::String(value) => properties.set(name, value)?,
+          Value::Number(value) => properties.set(name, &value.to_string())?,
+          _ => return Err(format!("Property {} must be a string or a number", name))
+        }
+      },
+    Some(_) => return Err("properties must be an object".to_string())
+  };
+  let body = match object.get("body") {
+    None | Some(Value::Null) => String::new(),
+    Some(Value::String(body)) => body.clone(),
+    Some(body) => body.to_string()
+  };
+  Ok(Envelope{ routing_key, headers, properties, body })
+}
+
+fn field_table(fields: &serde_json::Map<String, Value>) -> FieldTable {
+  FieldTable::from(fields.iter().map(|(name, value)| (name.as_str().into(), amqp_value(value))).collect::<BTreeMap<_, _>>())
+}
+
+// The brokers have no unsigned 64 bit integers, the bigger numbers are sent as doubles
+fn amqp_value(value: &Value) -> AMQPValue {
+  match value {
+    Value::Null => AMQPValue::Void,
+    Value::Bool(b) => AMQPValue::Boolean(*b),
+    Value::Number(n) =>
+      if let Some(i) = n.as_i64() {
+        AMQPValue::LongLongInt(i)
+      } else {
+        AMQPValue::Double(n.as_f64().unwrap_or(f64::NAN))
+      },
+    Value::String(s) => AMQPValue::LongString(s.as_str().into()),
+    Value::Array(values) => AMQPValue::FieldArray(values.iter().map(amqp_value).collect::<Vec<_>>().into()),
+    Value::Object(fields) => AMQPValue::FieldTable(field_table(fields))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_envelope_works() {
+    assert_eq!(Ok(Envelope{ routing_key: "my_key".to_string(), headers: None, properties: Properties::default(), body: "A message".to_string() }),
+               parse_envelope(r#"{"routing_key": "my_key", "body": "A message"}"#));
+    assert_eq!(Ok(Envelope{ routing_key: "".to_string(), headers: None, properties: Properties::default(), body: "".to_string() }),
+               parse_envelope("{}"));
+    assert_eq!(Ok(Envelope{ routing_key: "a|b".to_string(), headers: None, properties: Properties::default(), body: r#"{"id":1,"tags":["x,y"]}"#.to_string() }),
+               parse_envelope(r#"{"routing_key": "a|b", "body": {"id": 1, "tags": ["x,y"]}}"#));
+    assert_eq!(Ok(Envelope{ routing_key: "my_key".to_string(), headers: Some(FieldTable::from(btreemap!{
+      "X-Int".into() => AMQPValue::LongLongInt(-3),
+      "X-Float".into() => AMQPValue::Double(1.5),
+      "X-Bool".into() => AMQPValue::Boolean(true),
+      "X-Null".into() => AMQPValue::Void,
+      "X-Values".into() => AMQPValue::FieldArray(vec![
+        AMQPValue::LongString("a, b".into()),
+        AMQPValue::LongString("c; d".into()),
+      ].into()),
+      "X-Table".into() => AMQPValue::FieldTable(FieldTable::from(btreemap!{
+        "nested".into() => AMQPValue::Double(18446744073709551615.0)
+      }))
+    })), properties: Properties::default(), body: "A message".to_string() }),
+    parse_envelope(r#"{"routing_key": "my_key", "body": "A message",
+                       "headers": {"X-Int": -3, "X-Float": 1.5, "X-Bool": true, "X-Null": null,
+                                   "X-Values": ["a, b", "c; d"], "X-Table": {"nested": 18446744073709551615}}}"#));
+  }
+
+  #[test]
+  fn parse_envelope_with_properties_works() {
+    assert_eq!(Ok(Envelope{ routing_key: "my_key".to_string(), headers: None, properties: Properties{
+      content_type: Some("application/json".to_string()),
+      correlation_id: Some("42".to_string()),
+      priority: Some(5),
+      timestamp: Some(1500000000),
+      message_type: Some("order.created".to_string()),
+      ..Properties::default()
+    }, body: "{}".to_string() }),
+    parse_envelope(r#"{"routing_key": "my_key", "body": {},
+                       "properties": {"content_type": "application/json", "correlation_id": 42, "priority": 5,
+                                      "timestamp": 1500000000, "type": "order.created"}}"#));
+  }
+
+  #[test]
+  fn parse_envelope_fails_on_invalid_payload() {
+    assert!(parse_envelope("my_key|A message").is_err());
+    assert!(parse_envelope(r#"["my_key", "A message"]"#).is_err());
+    assert!(parse_envelope(r#"{"routing_key": 1}"#).is_err());
+    assert!(parse_envelope(r#"{"headers": ["X-My-Header"]}"#).is_err());
+    assert!(parse_envelope(r#"{"properties": {"priority": 256}}"#).is_err());
+    assert!(parse_envelope(r#"{"properties": {"reply_to": ["a", "b"]}}"#).is_err());
+    assert!(parse_envelope(r#"{"properties": {"cluster_id": "a"}}"#).is_err());
+  }
+}
@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::Type;
+use super::topology::Kind;
+
+#[derive(Debug)]
+pub enum BridgeError {
+  // Invalid BRIDGE_CHANNELS, binding option or config file
+  Config(String),
+  // The amqp entity of a binding doesn't exist on the broker
+  EntityNotFound(String),
+  // The amqp entity of a binding is of the other type than the one it states, or an exchange of another kind than the stated one
+  EntityTypeMismatch(String, Type, Option<Kind>),
+  Amqp(lapin::Error),
+  Postgres(tokio_postgres::Error),
+  Io(io::Error),
+  // The bridge isn't running anymore
+  Stopped
+}
+
+impl BridgeError {
+  /*
+   * The connection errors go away by reconnecting, the rest need a fix in the configuration, the broker or the database.
+   * A PostgreSQL error is only a connection error when the connection was lost or couldn't be made, e.g. not when
+   * the authentication or a statement failed.
+  */
+  pub fn is_connection_error(&self) -> bool {
+    match self {
+      BridgeError::Amqp(e) => matches!(e, lapin::Error::IOError(_) | lapin::Error::InvalidConnectionState(_) |
+                                          lapin::Error::InvalidChannelState(_) | lapin::Error::MissingHeartbeatError),
+      BridgeError::Postgres(e) => e.is_closed() || (e.code().is_none() && e.source().is_some_and(|cause| cause.is::<io::Error>())),
+      _ => false
+    }
+  }
+}
+
+impl fmt::Display for BridgeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      BridgeError::Config(e) => write!(f, "Invalid configuration: {}", e),
+      BridgeError::EntityNotFound(amqp_entity) => write!(f, "The amqp entity {:?} doesn't exist", amqp_entity),
+      BridgeError::EntityTypeMismatch(amqp_entity, Type::Exchange, Some(kind)) =>
+        write!(f, "The amqp entity {:?} is not a {} exchange", amqp_entity, format!("{:?}", kind).to_lowercase()),
+      BridgeError::EntityTypeMismatch(amqp_entity, Type::Exchange, None) => write!(f, "The amqp entity {:?} is an exchange, not a queue", amqp_entity),
+      BridgeError::EntityTypeMismatch(amqp_entity, Type::Queue, _) => write!(f, "The amqp entity {:?} is a queue, not an exchange", amqp_entity),
+      BridgeError::Amqp(e) => write!(f, "AMQP error: {}", e),
+      // The cause, e.g. of a failed TLS handshake, isn't part of the error message
+      BridgeError::Postgres(e) => match e.source() {
+        Some(cause) => write!(f, "PostgreSQL error: {}: {}", e, cause),
+        None => write!(f, "PostgreSQL error: {}", e)
+      },
+      BridgeError::Io(e) => write!(f, "IO error: {}", e),
+      BridgeError::Stopped => write!(f, "The bridge is stopped")
+    }
+  }
+}
+
+impl Error for BridgeError {}
+
+impl From<lapin::Error> for BridgeError {
+  fn from(e: lapin::Error) -> BridgeError {
+    BridgeError::Amqp(e)
+  }
+}
+
+impl From<tokio_postgres::Error> for BridgeError {
+  fn from(e: tokio_postgres::Error) -> BridgeError {
+    BridgeError::Postgres(e)
+  }
+}
+
+impl From<io::Error> for BridgeError {
+  fn from(e: io::Error) -> BridgeError {
+    BridgeError::Io(e)
+  }
+}
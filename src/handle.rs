@@ -0,0 +1,745 @@
+use std::collections::{BTreeMap, HashMap};
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::runtime;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task;
+use tokio_postgres::{Client, Notification};
+
+use super::{Binding, FailurePolicy, PgClientFactory, PgClients, SHUTDOWN_CHECK_INTERVAL, cdc, check_bindings, outbox, relay_notifications};
+use super::amqp::{AmqpConnection, AmqpConnector, AmqpTls, NodeOrder};
+use super::consumer::{self, Consumer, Endpoint, Subscription, check_consumers};
+use super::relay::{Relay, check_relays};
+use super::rpc::{Calls, Rpc, check_rpcs};
+use super::spool::{self, Spool, SpoolOptions};
+use super::error::BridgeError;
+use super::pg::{PgConnection, PgTls, tls_connector};
+use super::publisher::{Publisher, PublisherStats};
+use super::shutdown::Shutdown;
+use super::topology::Topology;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BindingState {
+  // Waiting for its AMQP channel
+  Starting,
+  Running,
+  // Its publisher finished, e.g. while the bridge is listening again after losing the PostgreSQL connection
+  Stopped,
+  // Its publisher couldn't start, e.g. the amqp entity doesn't exist
+  Failed(String)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BindingStatus {
+  pub pg_channel: String,
+  pub amqp_entity: String,
+  pub state: BindingState,
+  // Messages taken by the broker
+  pub published: u64,
+  // Messages that went to the failure path
+  pub undelivered: u64,
+  // AMQP node, as host:port, the binding publishes to while it's running
+  pub amqp_node: Option<String>
+}
+
+// A binding of the running bridge, its state and stats are shared with the publisher tasks
+#[derive(Clone)]
+struct Entry {
+  binding: Binding,
+  state: Arc<Mutex<BindingState>>,
+  stats: Arc<PublisherStats>
+}
+
+impl Entry {
+  fn new(binding: Binding) -> Entry {
+    Entry{ binding, state: Arc::new(Mutex::new(BindingState::Starting)), stats: Arc::new(PublisherStats::default()) }
+  }
+}
+
+// A channel can have several bindings, one per amqp entity
+type Key = (String, String);
+
+fn key(binding: &Binding) -> Key {
+  (binding.pg_channel.clone(), binding.amqp_entity.clone())
+}
+
+type Registry = Arc<Mutex<BTreeMap<Key, Entry>>>;
+
+// Sent to the listener, which finds the binding in the registry
+enum Command {
+  Add(Key),
+  Remove(Key)
+}
+
+#[derive(Clone)]
+struct Settings {
+  pg: PgClients,
+  amqp: AmqpConnector,
+  topology: Topology,
+  consumers: Vec<Consumer>,
+  relays: Vec<Relay>,
+  rpcs: Vec<Rpc>,
+  // Shared by the publishers and the RPCs, kept across reconnections
+  calls: Arc<Calls>,
+  spool: Option<SpoolOptions>,
+  delivery_mode: u8,
+  // Time the publishers get to finish when the listener stops, also without a shutdown request
+  shutdown_timeout: Duration
+}
+
+pub struct Bridge;
+
+impl Bridge {
+  pub fn builder() -> BridgeBuilder {
+    BridgeBuilder{ postgresql_uri: None, pg_tls: PgTls::default(), pg: None, amqp_uris: Vec::new(), amqp_node_order: NodeOrder::Ordered, amqp_tls: AmqpTls::default(), topology: Topology::default(), delivery_mode: 1, bindings: Vec::new(), consumers: Vec::new(), relays: Vec::new(), rpcs: Vec::new(), spool: None, shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT }
+  }
+}
+
+pub struct BridgeBuilder {
+  postgresql_uri: Option<String>,
+  pg_tls: PgTls,
+  pg: Option<PgClients>,
+  amqp_uris: Vec<String>,
+  amqp_node_order: NodeOrder,
+  amqp_tls: AmqpTls,
+  topology: Topology,
+  delivery_mode: u8,
+  bindings: Vec<Binding>,
+  consumers: Vec<Consumer>,
+  relays: Vec<Relay>,
+  rpcs: Vec<Rpc>,
+  spool: Option<SpoolOptions>,
+  shutdown_timeout: Duration
+}
+
+impl BridgeBuilder {
+  // Connects as its sslmode says, a client factory takes precedence
+  pub fn postgresql_uri(mut self, postgresql_uri: &str) -> BridgeBuilder {
+    self.postgresql_uri = Some(postgresql_uri.to_string());
+    self
+  }
+
+  // Certificates for the PostgreSQL URI connections
+  pub fn postgresql_tls(mut self, pg_tls: PgTls) -> BridgeBuilder {
+    self.pg_tls = pg_tls;
+    self
+  }
+
+  pub fn pg_client_factory<F: PgClientFactory + 'static>(mut self, factory: F) -> BridgeBuilder {
+    self.pg = Some(Arc::new(factory));
+    self
+  }
+
+  pub fn amqp_uri(mut self, amqp_uri: &str) -> BridgeBuilder {
+    self.amqp_uris = vec![amqp_uri.to_string()];
+    self
+  }
+
+  // The nodes of a cluster, the bridge fails over to the next one when its node is lost
+  pub fn amqp_uris(mut self, amqp_uris: Vec<String>) -> BridgeBuilder {
+    self.amqp_uris = amqp_uris;
+    self
+  }
+
+  pub fn amqp_node_order(mut self, amqp_node_order: NodeOrder) -> BridgeBuilder {
+    self.amqp_node_order = amqp_node_order;
+    self
+  }
+
+  // Used by amqps:// URIs
+  pub fn amqp_tls(mut self, amqp_tls: AmqpTls) -> BridgeBuilder {
+    self.amqp_tls = amqp_tls;
+    self
+  }
+
+  // Declared at startup and after every reconnect, before the bindings publish
+  pub fn topology(mut self, topology: Topology) -> BridgeBuilder {
+    self.topology = topology;
+    self
+  }
+
+  // Used by the bindings that don't set their own, 1 is non persistent and 2 persistent
+  pub fn delivery_mode(mut self, delivery_mode: u8) -> BridgeBuilder {
+    self.delivery_mode = delivery_mode;
+    self
+  }
+
+  pub fn binding(mut self, binding: Binding) -> BridgeBuilder {
+    self.bindings.push(binding);
+    self
+  }
+
+  pub fn bindings(mut self, bindings: Vec<Binding>) -> BridgeBuilder {
+    self.bindings.extend(bindings);
+    self
+  }
+
+  // Queues consumed into PostgreSQL
+  pub fn consumer(mut self, consumer: Consumer) -> BridgeBuilder {
+    self.consumers.push(consumer);
+    self
+  }
+
+  pub fn consumers(mut self, consumers: Vec<Consumer>) -> BridgeBuilder {
+    self.consumers.extend(consumers);
+    self
+  }
+
+  // Queues and exchanges relayed to PostgreSQL channels
+  pub fn relay(mut self, relay: Relay) -> BridgeBuilder {
+    self.relays.push(relay);
+    self
+  }
+
+  pub fn relays(mut self, relays: Vec<Relay>) -> BridgeBuilder {
+    self.relays.extend(relays);
+    self
+  }
+
+  // Reply queues written into PostgreSQL tables
+  pub fn rpc(mut self, rpc: Rpc) -> BridgeBuilder {
+    self.rpcs.push(rpc);
+    self
+  }
+
+  pub fn rpcs(mut self, rpcs: Vec<Rpc>) -> BridgeBuilder {
+    self.rpcs.extend(rpcs);
+    self
+  }
+
+  // Directory where the notifications are kept while the broker is unreachable
+  pub fn spool(mut self, spool: SpoolOptions) -> BridgeBuilder {
+    self.spool = Some(spool);
+    self
+  }
+
+  // Time the publishers get to deliver the pending messages once the bridge is stopped
+  pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> BridgeBuilder {
+    self.shutdown_timeout = shutdown_timeout;
+    self
+  }
+
+  /*
+   * Starts the bridge in the background on its own runtime, it can start without bindings and get them later
+   * through the handle.
+  */
+  pub fn start(self) -> Result<BridgeHandle, BridgeError> {
+    let pg: PgClients = match (self.pg, self.postgresql_uri) {
+      (Some(pg), _) => pg,
+      (None, Some(postgresql_uri)) => Arc::new(tls_connector(&postgresql_uri, &self.pg_tls)?),
+      (None, None) => return Err(BridgeError::Config("The bridge needs a PostgreSQL URI or client factory".to_string()))
+    };
+    let amqp = AmqpConnector::new(&self.amqp_uris, self.amqp_node_order, &self.amqp_tls)?;
+    self.topology.check()?;
+    let bindings = check_bindings(self.bindings.into_iter().map(Binding::finish).collect::<Result<Vec<Binding>, BridgeError>>()?)?;
+    for binding in &bindings {
+      self.topology.check_binding(binding)?;
+    }
+    let consumers = check_consumers(self.consumers.into_iter().map(Consumer::finish).collect::<Result<Vec<Consumer>, BridgeError>>()?)?;
+    let relays = check_relays(self.relays.into_iter().map(Relay::finish).collect::<Result<Vec<Relay>, BridgeError>>()?)?;
+    let rpcs = check_rpcs(self.rpcs.into_iter().map(Rpc::finish).collect::<Result<Vec<Rpc>, BridgeError>>()?)?;
+    let calls = Arc::new(Calls::new(&rpcs));
+    if let Some(ref spool) = self.spool {
+      spool.check()?;
+    }
+
+    let registry: Registry = Arc::new(Mutex::new(bindings.into_iter().map(|binding| (key(&binding), Entry::new(binding))).collect()));
+    let (commands, mut receiver) = mpsc::unbounded_channel();
+    let shutdown = Shutdown::new();
+    let settings = Settings{ pg, amqp, topology: self.topology, consumers, relays, rpcs, calls, spool: self.spool, delivery_mode: self.delivery_mode,
+                             shutdown_timeout: self.shutdown_timeout };
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+    let supervisor = {
+      let registry = registry.clone();
+      let shutdown = shutdown.clone();
+      thread::spawn(move || runtime.block_on(supervise(&settings, &registry, &mut receiver, &shutdown)))
+    };
+    Ok(BridgeHandle{
+      registry, shutdown, shutdown_timeout: self.shutdown_timeout,
+      commands, supervisor: Mutex::new(Some(supervisor))
+    })
+  }
+}
+
+/*
+ * Controls a running bridge, it can be shared between threads. Dropping it leaves the bridge running in the
+ * background.
+*/
+pub struct BridgeHandle {
+  registry: Registry,
+  shutdown: Shutdown,
+  shutdown_timeout: Duration,
+  commands: UnboundedSender<Command>,
+  supervisor: Mutex<Option<JoinHandle<Result<(), BridgeError>>>>
+}
+
+impl BridgeHandle {
+  // The bridge stops taking notifications and the publishers get the shutdown timeout to deliver the pending ones
+  pub fn stop(&self) {
+    self.shutdown.request(self.shutdown_timeout);
+  }
+
+  /*
+   * Waits until the bridge is stopped or a binding it started with can't start, the latter is returned as an error.
+   * Joining again returns right away.
+  */
+  pub fn join(&self) -> Result<(), BridgeError> {
+    let supervisor = self.supervisor.lock().unwrap().take();
+    match supervisor {
+      Some(supervisor) => supervisor.join().unwrap_or_else(|e| panic::resume_unwind(e)),
+      None => Ok(())
+    }
+  }
+
+  pub fn status(&self) -> Vec<BindingStatus> {
+    self.registry.lock().unwrap().values().map(|entry| BindingStatus{
+      pg_channel: entry.binding.pg_channel.clone(),
+      amqp_entity: entry.binding.amqp_entity.clone(),
+      state: entry.state.lock().unwrap().clone(),
+      published: entry.stats.published(),
+      undelivered: entry.stats.undelivered(),
+      amqp_node: entry.stats.node()
+    }).collect()
+  }
+
+  /*
+   * The binding starts in the background, whether it's running can be seen in its status.
+   * Bindings that fail to start are kept as failed until they're removed.
+  */
+  pub fn add_binding(&self, binding: Binding) -> Result<(), BridgeError> {
+    let binding = binding.finish()?;
+    let mut registry = self.registry.lock().unwrap();
+    let channel_bindings = registry.values()
+      .filter(|entry| entry.binding.pg_channel == binding.pg_channel)
+      .map(|entry| entry.binding.clone());
+    check_bindings(channel_bindings.chain(Some(binding.clone())).collect())?;
+    self.commands.send(Command::Add(key(&binding))).map_err(|_| BridgeError::Stopped)?;
+    registry.insert(key(&binding), Entry::new(binding));
+    Ok(())
+  }
+
+  // Removes all the bindings of the channel, the notifications already taken are still delivered
+  pub fn remove_binding(&self, pg_channel: &str) -> Result<(), BridgeError> {
+    let keys: Vec<Key> = self.registry.lock().unwrap().keys().filter(|(channel, _)| channel == pg_channel).cloned().collect();
+    if keys.is_empty() {
+      return Err(BridgeError::Config(format!("No binding for the PostgreSQL channel {:?}", pg_channel)));
+    }
+    for (pg_channel, amqp_entity) in keys {
+      self.remove_target(&pg_channel, &amqp_entity)?;
+    }
+    Ok(())
+  }
+
+  // Removes one of the bindings of the channel, the bridge stops listening on it with the last one
+  pub fn remove_target(&self, pg_channel: &str, amqp_entity: &str) -> Result<(), BridgeError> {
+    let key = (pg_channel.to_string(), amqp_entity.to_string());
+    let mut registry = self.registry.lock().unwrap();
+    if registry.remove(&key).is_none() {
+      return Err(BridgeError::Config(format!("No binding of the PostgreSQL channel {:?} to {:?}", pg_channel, amqp_entity)));
+    }
+    self.commands.send(Command::Remove(key)).map_err(|_| BridgeError::Stopped)
+  }
+}
+
+/*
+ * Listens again whenever the PostgreSQL connection is lost, until the bridge is stopped or a binding can't start.
+ * The connection is retried with a backoff while the server is unreachable.
+*/
+async fn supervise(settings: &Settings, registry: &Registry, commands: &mut UnboundedReceiver<Command>, shutdown: &Shutdown) -> Result<(), BridgeError> {
+  let mut i = 1;
+  loop {
+    match listen(settings, registry, commands, shutdown).await {
+      Ok(()) => i = 1,
+      Err(e) if e.is_connection_error() => {
+        println!("{}", e);
+        let time = Duration::from_secs(i);
+        println!("Retrying the PostgreSQL connection in {:?} seconds..", time.as_secs());
+        shutdown.sleep(time).await;
+        i *= 2;
+        if i > 32 { i = 1 };
+      },
+      Err(e) => return Err(e)
+    }
+    if shutdown.is_requested() {
+      return Ok(());
+    }
+  }
+}
+
+// What the publishers of a listener share, the PostgreSQL client of the listener connection and the AMQP connection
+struct Session {
+  settings: Settings,
+  pg_client: Arc<Client>,
+  amqp: Arc<AmqpConnection>,
+  shutdown: Shutdown
+}
+
+type Ready = UnboundedSender<Result<(), BridgeError>>;
+
+struct Listener {
+  session: Arc<Session>,
+  // Senders of the notifications to the binding publishers, by channel and amqp entity
+  senders: HashMap<String, HashMap<String, UnboundedSender<String>>>,
+  // The consumers, relays and RPCs stop once theirs is dropped
+  consumer_stops: Vec<UnboundedSender<()>>,
+  tasks: Vec<task::JoinHandle<()>>
+}
+
+/*
+ * Returns Ok when the listener connection is lost or after a shutdown request, once all the publishers have finished
+ * and closed their AMQP channels. The errors of the bindings the listener started with are returned before any
+ * notification is taken.
+*/
+async fn listen(settings: &Settings, registry: &Registry, commands: &mut UnboundedReceiver<Command>, shutdown: &Shutdown) -> Result<(), BridgeError> {
+  println!("Attempting to connect to PostgreSQL..");
+  let PgConnection{ client, mut notifications } = settings.pg.connect().await?;
+  println!("Connection to PostgreSQL successful");
+
+  // A single connection listens on all the channels, the bindings share its client and a single AMQP connection
+  let mut listener = Listener{
+    session: Arc::new(Session{
+      settings: settings.clone(), pg_client: Arc::new(client),
+      amqp: Arc::new(AmqpConnection::new(settings.amqp.clone(), settings.topology.clone(), shutdown.clone())), shutdown: shutdown.clone()
+    }),
+    senders: HashMap::new(),
+    consumer_stops: Vec::new(),
+    tasks: Vec::new()
+  };
+  // The failed bindings are left out unless they keep retrying
+  let entries: Vec<Entry> = registry.lock().unwrap().values().filter(|entry| match *entry.state.lock().unwrap() {
+    BindingState::Failed(_) => entry.binding.options.on_failure == FailurePolicy::Retry,
+    _ => true
+  }).cloned().collect();
+  let mut pg_channels: Vec<&str> = entries.iter().map(|entry| entry.binding.pg_channel.as_str()).collect();
+  pg_channels.dedup();
+  for pg_channel in pg_channels {
+    listener.listen_on(pg_channel).await?;
+  }
+  let (ready_sender, mut ready) = mpsc::unbounded_channel();
+  for entry in entries {
+    listener.start_publisher(entry, Some(ready_sender.clone()));
+  }
+  for consumer in &settings.consumers {
+    listener.start_consumer(Endpoint::Consumer(consumer.clone()), ready_sender.clone());
+  }
+  for relay in &settings.relays {
+    listener.start_consumer(Endpoint::Relay(relay.clone()), ready_sender.clone());
+  }
+  for rpc in &settings.rpcs {
+    listener.start_consumer(Endpoint::Rpc(rpc.clone(), settings.calls.clone()), ready_sender.clone());
+  }
+  drop(ready_sender);
+  // The publishers can wait long for the AMQP connection, the shutdown doesn't wait for them
+  loop {
+    if shutdown.is_requested() {
+      println!("Shutting down before all the bindings started");
+      listener.abort().await;
+      return Ok(());
+    }
+    tokio::select! {
+      started = ready.recv() => match started {
+        Some(Err(e)) => {
+          listener.abort().await;
+          return Err(e);
+        },
+        Some(Ok(())) => {},
+        None => break
+      },
+      _ = tokio::time::sleep(SHUTDOWN_CHECK_INTERVAL) => {}
+    }
+  }
+
+  loop {
+    if shutdown.is_requested() {
+      break;
+    }
+    tokio::select! {
+      Some(command) = commands.recv() => {
+        if let Err(e) = listener.apply(command, registry).await {
+          error!("{:?}", e);
+          break;
+        }
+      },
+      notification = notifications.recv() => match notification {
+        Some(notification) => listener.dispatch(notification),
+        None => {
+          error!("The PostgreSQL connection was lost");
+          break;
+        }
+      },
+      _ = tokio::time::sleep(SHUTDOWN_CHECK_INTERVAL) => {}
+    }
+  }
+  if shutdown.is_requested() {
+    println!("Shutting down, no more notifications will be taken");
+    if let Err(e) = listener.session.pg_client.batch_execute("UNLISTEN *").await {
+      error!("{:?}", e);
+    }
+  }
+  listener.stop().await;
+  Ok(())
+}
+
+impl Listener {
+  async fn listen_on(&self, pg_channel: &str) -> Result<(), BridgeError> {
+    self.session.pg_client.batch_execute(format!("LISTEN {}", pg_channel).as_str()).await?;
+    println!("Listening on {}...", pg_channel);
+    Ok(())
+  }
+
+  // The publisher reports whether it could start to the given sender, or only through its state when there's none
+  fn start_publisher(&mut self, entry: Entry, ready: Option<Ready>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    self.senders.entry(entry.binding.pg_channel.clone()).or_default().insert(entry.binding.amqp_entity.clone(), sender);
+    self.tasks.push(tokio::spawn(run_publisher(self.session.clone(), entry, receiver, ready)));
+  }
+
+  fn start_consumer(&mut self, endpoint: Endpoint, ready: Ready) {
+    let (stop, stopped) = mpsc::unbounded_channel();
+    self.consumer_stops.push(stop);
+    self.tasks.push(tokio::spawn(run_consumer(self.session.clone(), endpoint, stopped, ready)));
+  }
+
+  // Sends the notification payload to every publisher of its channel, a stopped one doesn't keep it from the others
+  fn dispatch(&self, notification: Notification) {
+    match self.senders.get(notification.channel()) {
+      Some(publishers) => {
+        for (amqp_entity, publisher) in publishers {
+          if publisher.send(notification.payload().to_string()).is_err() {
+            error!("The publisher of {:?} -> {:?} stopped, dropping notification {:?}", notification.channel(), amqp_entity, notification.payload());
+          }
+        }
+      },
+      None => warn!("Notification on {:?} without a binding", notification.channel())
+    }
+  }
+
+  /*
+   * Applies a binding change of the handle. The commands that are already applied are skipped, e.g. a binding added
+   * while the bridge was listening again is in the registry by the time the listener starts.
+  */
+  async fn apply(&mut self, command: Command, registry: &Registry) -> Result<(), BridgeError> {
+    match command {
+      Command::Add(key) => {
+        let (ref pg_channel, ref amqp_entity) = key;
+        let listening = self.senders.get(pg_channel);
+        let entry = match registry.lock().unwrap().get(&key) {
+          Some(entry) if !listening.is_some_and(|publishers| publishers.contains_key(amqp_entity)) => entry.clone(),
+          _ => return Ok(())
+        };
+        if listening.is_none() {
+          self.listen_on(pg_channel).await?;
+        }
+        self.start_publisher(entry, None);
+      },
+      Command::Remove((pg_channel, amqp_entity)) => {
+        if let Some(publishers) = self.senders.get_mut(&pg_channel) {
+          publishers.remove(&amqp_entity);
+          if publishers.is_empty() {
+            self.senders.remove(&pg_channel);
+            self.session.pg_client.batch_execute(format!("UNLISTEN {}", pg_channel).as_str()).await?;
+            println!("Stopped listening on {}", pg_channel);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  // The publishers stop once their channel is closed
+  async fn stop(self) {
+    drop(self.senders);
+    drop(self.consumer_stops);
+    join_tasks(self.tasks, &self.session.shutdown, self.session.settings.shutdown_timeout).await;
+    self.session.amqp.close().await;
+  }
+
+  // The publishers that are still waiting for the AMQP connection don't get to finish
+  async fn abort(self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+    self.stop().await;
+  }
+}
+
+async fn run_publisher(session: Arc<Session>, entry: Entry, mut notifications: UnboundedReceiver<String>, ready: Option<Ready>) {
+  let Entry{ binding, state, stats } = entry;
+  let mut ready = ready;
+  let mut i = 1;
+  let mut publisher = loop {
+    *state.lock().unwrap() = BindingState::Starting;
+    let publisher = Publisher::new(session.amqp.clone(), binding.clone(), session.settings.delivery_mode, session.pg_client.clone(), stats.clone(), session.settings.calls.clone()).await;
+    let e = match publisher {
+      Ok(publisher) => break publisher,
+      Err(e) => e
+    };
+    *state.lock().unwrap() = BindingState::Failed(e.to_string());
+    match (ready.take(), binding.options.on_failure) {
+      (Some(ready), FailurePolicy::Stop) => {
+        let _ = ready.send(Err(e));
+        return;
+      },
+      (ready, on_failure) => {
+        error!("{:?} -> {:?} could not start: {}", binding.pg_channel, binding.amqp_entity, e);
+        // The bridge goes on without it
+        if let Some(ready) = ready {
+          let _ = ready.send(Ok(()));
+        }
+        if on_failure != FailurePolicy::Retry {
+          return;
+        }
+      }
+    }
+    let time = Duration::from_secs(i);
+    println!("Retrying {:?} -> {:?} in {:?} seconds..", binding.pg_channel, binding.amqp_entity, time.as_secs());
+    session.shutdown.sleep(time).await;
+    if session.shutdown.is_requested() {
+      return;
+    }
+    i *= 2;
+    if i > 32 { i = 1 };
+  };
+  *state.lock().unwrap() = BindingState::Running;
+  if let Some(ready) = ready {
+    let _ = ready.send(Ok(()));
+  }
+
+  let shutdown = &session.shutdown;
+  match (&binding.options.outbox, &binding.options.slot) {
+    (Some(outbox), _) => outbox::relay_outbox(&session.settings.pg, &mut publisher, &binding, outbox, &mut notifications, shutdown).await,
+    (_, Some(slot)) => cdc::relay_changes(&session.pg_client, &mut publisher, &binding, slot, &mut notifications, shutdown).await,
+    _ => match session.settings.spool.as_ref().map(|options| Spool::open(&binding, options)) {
+      Some(Ok(spool)) => spool::relay_spooled(&mut publisher, &session.pg_client, &binding, spool, &mut notifications, shutdown).await,
+      spool => {
+        if let Some(Err(e)) = spool {
+          error!("{:?} -> {:?} could not open its spool, the messages are kept in memory: {}", binding.pg_channel, binding.amqp_entity, e);
+        }
+        relay_notifications(&mut publisher, &session.pg_client, &binding, &mut notifications, shutdown).await
+      }
+    }
+  }
+
+  publisher.close().await;
+  *state.lock().unwrap() = BindingState::Stopped;
+}
+
+/*
+ * Waits for the tasks until the shutdown deadline or the timeout, whichever comes first, the ones still running then
+ * are aborted. Every task is awaited once.
+*/
+async fn join_tasks(tasks: Vec<task::JoinHandle<()>>, shutdown: &Shutdown, timeout: Duration) {
+  let deadline = tokio::time::sleep(timeout);
+  tokio::pin!(deadline);
+  let mut tasks = tasks.into_iter();
+  let mut running = Vec::new();
+  for mut task in tasks.by_ref() {
+    tokio::select! {
+      _ = &mut task => {},
+      _ = shutdown.overdue() => {
+        running.push(task);
+        break;
+      },
+      _ = &mut deadline => {
+        running.push(task);
+        break;
+      }
+    }
+  }
+  running.extend(tasks);
+  for task in &running {
+    task.abort();
+  }
+  for task in running {
+    let _ = task.await;
+  }
+}
+
+// A consumer, relay or RPC that can't start stops the bridge like the bindings it started with
+async fn run_consumer(session: Arc<Session>, endpoint: Endpoint, mut stop: UnboundedReceiver<()>, ready: Ready) {
+  let subscription = match Subscription::new(&session.amqp, &session.pg_client, &endpoint, None).await {
+    Ok(subscription) => subscription,
+    Err(e) => {
+      let _ = ready.send(Err(e));
+      return;
+    }
+  };
+  let _ = ready.send(Ok(()));
+  consumer::relay_deliveries(&session.amqp, &session.pg_client, &endpoint, subscription, &mut stop).await;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::pg::PgConnect;
+
+  // Blocks the listener until the test drops the sender, then fails like a database that doesn't exist
+  struct BlockedPgClientFactory(tokio::sync::Mutex<UnboundedReceiver<()>>);
+
+  impl PgClientFactory for BlockedPgClientFactory {
+    fn connect(&self) -> PgConnect<'_> {
+      Box::pin(async move {
+        self.0.lock().await.recv().await;
+        Err(BridgeError::Config("No database".to_string()))
+      })
+    }
+  }
+
+  fn blocked_bridge() -> (UnboundedSender<()>, BridgeBuilder) {
+    let (unblock, blocked) = mpsc::unbounded_channel();
+    (unblock, Bridge::builder().pg_client_factory(BlockedPgClientFactory(tokio::sync::Mutex::new(blocked))).amqp_uri("amqp://localhost//"))
+  }
+
+  #[test]
+  fn bridge_handle_works() {
+    let (unblock, builder) = blocked_bridge();
+    let bridge = builder.binding(Binding::new("pgchannel1", "exchange1")).start().unwrap();
+    assert!(bridge.add_binding(Binding::new("pgchannel1", "exchange1")).is_err());
+    assert!(bridge.add_binding(Binding::new("pgchannel1", "queue1")).is_ok());
+    assert!(bridge.add_binding(Binding::new("pgchannel1", "queue2")).is_ok());
+    assert!(bridge.add_binding(Binding::new("pgchannel2", "queue2")).is_ok());
+    assert!(bridge.remove_target("pgchannel1", "queue1").is_ok());
+    assert!(bridge.remove_target("pgchannel1", "queue1").is_err());
+    assert!(bridge.remove_binding("pgchannel1").is_ok());
+    assert!(bridge.remove_binding("pgchannel1").is_err());
+    assert_eq!(vec![BindingStatus{pg_channel: "pgchannel2".to_string(), amqp_entity: "queue2".to_string(),
+                                  state: BindingState::Starting, published: 0, undelivered: 0, amqp_node: None}], bridge.status());
+    drop(unblock);
+    assert!(bridge.join().is_err());
+    assert!(bridge.join().is_ok());
+    assert!(bridge.add_binding(Binding::new("pgchannel3", "queue3")).is_err());
+  }
+
+  #[test]
+  fn join_tasks_aborts_the_tasks_past_the_timeout() {
+    let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+      let finished = tokio::spawn(async {});
+      tokio::task::yield_now().await;
+      let stuck = tokio::spawn(std::future::pending());
+      let quick = tokio::spawn(tokio::time::sleep(Duration::from_millis(10)));
+      let start = std::time::Instant::now();
+      join_tasks(vec![finished, quick, stuck], &Shutdown::new(), Duration::from_millis(100)).await;
+      assert!(start.elapsed() >= Duration::from_millis(100));
+      assert!(start.elapsed() < Duration::from_secs(5));
+    });
+  }
+
+  #[test]
+  fn bridge_builder_fails_on_invalid_settings() {
+    assert!(Bridge::builder().amqp_uri("amqp://localhost//").start().is_err());
+    let (_unblock, builder) = blocked_bridge();
+    assert!(builder.bindings(vec![Binding::new("pgchannel1", "exchange1"), Binding::new("pgchannel1", "exchange1")]).start().is_err());
+    let (_unblock, builder) = blocked_bridge();
+    assert!(builder.binding(Binding::new(" ", "exchange1")).start().is_err());
+    let (_unblock, builder) = blocked_bridge();
+    let mut binding = Binding::new("pgchannel1", "exchange1");
+    binding.options.outbox = Some("bridge.outbox".to_string());
+    binding.options.slot = Some("bridge_slot".to_string());
+    assert!(builder.binding(binding).start().is_err());
+  }
+}
@@ -1,44 +1,185 @@
-extern crate amqp;
-extern crate fallible_iterator;
-extern crate r2d2;
-extern crate r2d2_postgres;
-extern crate postgres;
+extern crate fastrand;
+extern crate lapin;
+extern crate native_tls;
+extern crate postgres_native_tls;
+extern crate serde;
+extern crate serde_json;
+extern crate tokio;
+extern crate tokio_postgres;
+extern crate toml;
 #[macro_use] extern crate log;
-#[macro_use] extern crate maplit;
+#[cfg(test)] #[macro_use] extern crate maplit;
 
+mod amqp;
+mod cdc;
+pub mod config;
+mod consumer;
+mod envelope;
+pub mod error;
+mod handle;
+mod outbox;
+pub mod pg;
+pub mod properties;
+mod publisher;
+mod reference;
+mod relay;
+mod rpc;
+pub mod routing;
+pub mod shutdown;
+mod spool;
+mod tls;
+pub mod topology;
 
-use amqp::{Session, Basic, protocol, Channel, Table, AMQPError, TableEntry};
-use fallible_iterator::FallibleIterator;
-use r2d2::{Pool, PooledConnection};
-use r2d2_postgres::{PostgresConnectionManager, postgres::NoTls};
+use lapin::types::{AMQPValue, FieldTable};
+use serde::Deserialize;
 use std::default::Default;
-use std::thread;
-use std::thread::JoinHandle;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_postgres::Client;
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
-enum Type {
+use error::BridgeError;
+use properties::Properties;
+use publisher::{Message, Publisher};
+use reference::parse_reference;
+use routing::Rule;
+use shutdown::Shutdown;
+use topology::Kind;
+
+pub use amqp::{AmqpTls, NodeOrder};
+pub use consumer::{Consumer, ConsumerOptions, OnError, parse_bridge_consumers};
+pub use handle::{Bridge, BridgeBuilder, BridgeHandle, BindingStatus, BindingState};
+pub use pg::{PgClientFactory, PgConnection, PgConnector, PgTls};
+pub use relay::{Relay, RelayOptions, parse_bridge_relays};
+pub use rpc::{Rpc, RpcOptions, parse_bridge_rpcs};
+pub use spool::{Overflow, SpoolOptions};
+pub use topology::Topology;
+
+type PgClients = Arc<dyn PgClientFactory>;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Type {
   Exchange,
   Queue
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
-struct Binding{
-  pg_channel: String,
-  amqp_entity: String
+pub struct Binding{
+  pub pg_channel: String,
+  pub amqp_entity: String,
+  pub options: BindingOptions
 }
 
-//Used for channel ids
-struct ChannelCounter{
-  counter: u16
+impl Binding {
+  pub fn new(pg_channel: &str, amqp_entity: &str) -> Binding {
+    Binding{ pg_channel: pg_channel.to_string(), amqp_entity: amqp_entity.to_string(), options: BindingOptions::default() }
+  }
+
+  // Checks a binding that wasn't parsed from BRIDGE_CHANNELS or the config file
+  fn finish(self) -> Result<Binding, BridgeError> {
+    if self.pg_channel.trim().is_empty() || self.amqp_entity.trim().is_empty() {
+      return Err(BridgeError::Config(format!("Bindings need a PostgreSQL channel and an amqp entity, got {:?} and {:?}", self.pg_channel, self.amqp_entity)));
+    }
+    Ok(Binding{ options: self.options.finish()?, ..self })
+  }
 }
-impl ChannelCounter {
-  pub fn new() -> ChannelCounter {
-    ChannelCounter { counter: 0 }
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+  Pipe,
+  Json
+}
+
+// What a binding does when its publisher can't start, the other bindings of its channel aren't affected by the last two
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+  // Stops the bridge when it's one of the bindings the bridge started with
+  Stop,
+  // Leaves it failed
+  Skip,
+  // Tries again with a backoff, the notifications are kept meanwhile
+  Retry
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct BindingOptions{
+  // Skips finding out whether the amqp entity is an exchange or a queue
+  pub entity_type: Option<Type>,
+  // Kind of the exchange, implies the exchange entity type
+  pub exchange_kind: Option<Kind>,
+  // Overrides the delivery mode of the bridge
+  pub delivery_mode: Option<u8>,
+  // Routing key of the messages that don't have one
+  pub routing_key: Option<String>,
+  // Prepended to the routing key of every message
+  pub routing_key_prefix: Option<String>,
+  // Routes the messages by their content, tried in order
+  pub rules: Vec<Rule>,
+  // Format of the notification and outbox payloads
+  pub format: PayloadFormat,
+  // Default AMQP properties of the messages
+  pub properties: Properties,
+  // Put the channel in confirm mode and wait for the broker ack of every message
+  pub confirm: bool,
+  // Times a nacked message is republished before it goes to the failure path
+  pub retries: u32,
+  // Exchange that receives the messages the broker refused to take or couldn't route
+  pub fallback: Option<String>,
+  // Table where those messages are inserted
+  pub fallback_table: Option<String>,
+  // Table polled for messages instead of taking them from the notifications
+  pub outbox: Option<String>,
+  // Logical replication slot whose row changes are published instead of the notifications
+  pub slot: Option<String>,
+  // Table of the payloads the notifications reference when they're over the NOTIFY limit
+  pub payload_table: Option<String>,
+  // Publication with the tables the slot decodes, defaults to the slot name
+  pub publication: Option<String>,
+  // Seconds between outbox or slot polls
+  pub poll_interval: u64,
+  pub on_failure: FailurePolicy
+}
+
+impl Default for BindingOptions {
+  fn default() -> BindingOptions {
+    BindingOptions {
+      entity_type: None, exchange_kind: None, delivery_mode: None, routing_key: None, routing_key_prefix: None, rules: Vec::new(), format: PayloadFormat::Pipe, properties: Properties::default(),
+      confirm: false, retries: 3, fallback: None, fallback_table: None, outbox: None, slot: None, payload_table: None, publication: None, poll_interval: 5,
+      on_failure: FailurePolicy::Stop
+    }
   }
-  pub fn inc(&mut self) -> u16 {
-    self.counter += 1;
-    self.counter
+}
+
+impl BindingOptions {
+  // Checks the options and sets the ones implied by others
+  fn finish(mut self) -> Result<BindingOptions, BridgeError> {
+    for rule in &self.rules {
+      rule.check().map_err(BridgeError::Config)?;
+    }
+    if self.exchange_kind.is_some() {
+      if self.entity_type == Some(Type::Queue) {
+        return Err(BridgeError::Config("A queue binding can't have an exchange kind".to_string()));
+      }
+      self.entity_type = Some(Type::Exchange);
+    }
+    if self.outbox.is_some() && self.slot.is_some() {
+      return Err(BridgeError::Config("A binding can't have both an outbox and a slot".to_string()));
+    }
+    if self.payload_table.is_some() && (self.outbox.is_some() || self.slot.is_some()) {
+      return Err(BridgeError::Config("Only the notification bindings can have a payload_table".to_string()));
+    }
+    // Outbox rows are only deleted and slots only advanced once the broker confirms the messages
+    if self.outbox.is_some() || self.slot.is_some() {
+      self.confirm = true;
+    }
+    // Row changes are published as json objects
+    if self.slot.is_some() && self.properties.content_type.is_none() {
+      self.properties.content_type = Some("application/json".to_string());
+    }
+    Ok(self)
   }
 }
 
@@ -47,156 +188,223 @@ const HEADERS_SEPARATOR: char = ';';
 const HEADER_NAME_VALUE_SEPARATOR: char = ':';
 const HEADER_VALUES_SEPARATOR: char = ',';
 
-pub fn start(pool: Pool<PostgresConnectionManager<NoTls>>, amqp_uri: &str, bridge_channels: &str, delivery_mode: &u8){
-  let mut children = Vec::new();
+const CHANNEL_SEPARATOR: char = ':';
+const EXCHANGE_PREFIX: &str = "exchange:";
+const QUEUE_PREFIX: &str = "queue:";
+
+const OPTIONS_SEPARATOR: char = '?';
+const OPTION_SEPARATOR: char = '&';
+const OPTION_NAME_VALUE_SEPARATOR: char = '=';
+
+// Interval at which the listener checks for a shutdown request while there are no notifications
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// Interval at which an idle binding sends the messages the broker returned meanwhile to the failure path
+const RETURNED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/*
+ * Runs the bindings of BRIDGE_CHANNELS, listening again whenever the PostgreSQL connection is lost.
+ * Only returns when a binding can't start, Bridge::builder gives a handle to control the bridge instead.
+*/
+pub fn start(postgresql_uri: &str, amqp_uri: &str, bridge_channels: &str, delivery_mode: &u8) -> Result<(), BridgeError>{
+  Bridge::builder()
+    .postgresql_uri(postgresql_uri)
+    .amqp_uri(amqp_uri)
+    .delivery_mode(*delivery_mode)
+    .bindings(parse_bridge_channels(bridge_channels)?)
+    .start()?
+    .join()
+}
 
-  for binding in parse_bridge_channels(bridge_channels){
-    children.push(
-      spawn_listener_publisher(pool.get().unwrap(), amqp_uri.to_string(), binding, delivery_mode.to_owned()))
+// The notifications still pending at the shutdown deadline go to the failure path
+async fn relay_notifications(publisher: &mut Publisher, pg_client: &Client, binding: &Binding, notifications: &mut UnboundedReceiver<String>, shutdown: &Shutdown){
+  while let Some(notification) = next_notification(publisher, notifications).await {
+    if let Err((e, message)) = publish_notification(publisher, pg_client, binding, &notification, shutdown).await {
+      error!("{:?}", e);
+      if binding.options.confirm && publisher.send_to_fallback(&message, &e.to_string()).await {
+        delete_payload(pg_client, binding, &notification).await;
+      }
+    }
   }
+}
 
-  for child in children{
-    let _ = child.join();
+async fn next_notification(publisher: &mut Publisher, notifications: &mut UnboundedReceiver<String>) -> Option<String>{
+  loop {
+    match tokio::time::timeout(RETURNED_CHECK_INTERVAL, notifications.recv()).await {
+      Ok(notification) => return notification,
+      Err(_) => publisher.handle_returned().await
+    }
   }
 }
 
-fn spawn_listener_publisher(mut pg_conn: PooledConnection<PostgresConnectionManager<NoTls>>,
-                            amqp_uri: String, binding: Binding, delivery_mode: u8) -> JoinHandle<()> {
-  thread::spawn(move ||{
-
-    let mut channel_counter = ChannelCounter::new();
-    let mut session = wait_for_amqp_session(&amqp_uri, binding.pg_channel.as_str());
-    let amqp_entity_type = match get_amq_entity_type(
-      &mut session.open_channel(channel_counter.inc()).unwrap(),
-      &mut session.open_channel(channel_counter.inc()).unwrap(),
-      &binding.amqp_entity){
-        None      => {
-          error!("The amqp entity {:?} doesn't exist", binding.amqp_entity);
-          std::process::exit(1);
+/*
+ * A referenced payload is fetched from the payload table of the binding, its row is only deleted once the broker takes
+ * the message or a fallback does, otherwise it's kept so the payload can be recovered. The connection errors are
+ * returned with the message, which hasn't taken the failure path yet.
+*/
+async fn publish_notification(publisher: &mut Publisher, pg_client: &Client, binding: &Binding, notification: &str, shutdown: &Shutdown) -> Result<(), (BridgeError, Message)>{
+  let reference = match (&binding.options.payload_table, parse_reference(notification)) {
+    (Some(payload_table), Some(reference)) => {
+      let fetched = match reference {
+        Ok(reference) => reference.fetch(pg_client, payload_table).await.map(|payload| (reference, payload)),
+        Err(e) => Err(e)
+      };
+      match fetched {
+        Ok(fetched) => Some(fetched),
+        Err(e) => {
+          publisher.send_invalid_payload_to_fallback(notification, &e).await;
+          return Ok(());
         }
-        Some(typ) => typ
-    };
-    let mut local_channel = session.open_channel(channel_counter.inc()).unwrap();
-
-    let listen_command = format!("LISTEN {}", binding.pg_channel);
-    pg_conn.execute(listen_command.as_str(), &[]).unwrap();
-
-    println!("Listening on {}...", binding.pg_channel);
-
-    let mut notifications = pg_conn.notifications();
-    let mut it = notifications.blocking_iter();
-
-    while let Ok(Some(notification)) = it.next() {
-      let (routing_key, message, headers) = parse_notification(&notification.payload());
-      let (exchange, key) =
-        if amqp_entity_type == Type::Exchange {
-          (binding.amqp_entity.as_str(), routing_key)
-        } else {
-          ("", binding.amqp_entity.as_str())
-        };
-
-      let mut publication = local_channel.basic_publish(
-          exchange, key, true, false,
-          protocol::basic::BasicProperties{
-            content_type: Some("text".to_string()),
-            headers,
-            delivery_mode: Some(delivery_mode),
-            ..Default::default()
-          },
-          message.as_bytes().to_vec());
-
-      // When RMQ connection is lost retry it
-      if let Err(e@AMQPError::IoError(_)) = publication {
-        error!("{:?}", e);
-        session = wait_for_amqp_session(amqp_uri.as_str(), binding.pg_channel.as_str());
-        local_channel = match get_amq_entity_type(
-          &mut session.open_channel(channel_counter.inc()).unwrap(),
-          &mut session.open_channel(channel_counter.inc()).unwrap(),
-          &binding.amqp_entity){
-            None      => {
-              error!("The amqp entity {:?} doesn't exist", binding.amqp_entity);
-              std::process::exit(1);
-            },
-            Some(_) => session.open_channel(channel_counter.inc()).unwrap()
-        };
-        // Republish message
-        publication =
-          local_channel.basic_publish(
-            exchange, key, true, false,
-            protocol::basic::BasicProperties{ content_type: Some("text".to_string()), delivery_mode: Some(delivery_mode), ..Default::default()},
-            message.as_bytes().to_vec());
       }
-
-      match publication{
-        Ok(_) => {
-          info!("{:?} -> {:?} {:?} ( routing_key: {:?}, message: {:?} )",
-                binding.pg_channel, amqp_entity_type, binding.amqp_entity, routing_key, message);
-        },
-        Err(e)  => error!("{:?}", e)
+    },
+    _ => None
+  };
+  let payload = reference.as_ref().map_or(notification, |(_, payload)| payload.as_str());
+  let message = match publisher.message_from_payload(payload) {
+    Ok(message) => message,
+    Err(e) => {
+      publisher.send_invalid_payload_to_fallback(payload, &e).await;
+      return Ok(());
+    }
+  };
+  let handled = if shutdown.is_overdue() {
+    publisher.send_to_fallback(&message, "shutdown").await
+  } else {
+    match publisher.deliver(&message).await {
+      Ok(delivery) => delivery.is_handled(),
+      // Stopped while waiting for the broker to come back
+      Err(e) if e.is_connection_error() || matches!(e, BridgeError::Stopped) => return Err((e, message)),
+      Err(e) => {
+        error!("{:?}", e);
+        binding.options.confirm && publisher.send_to_fallback(&message, &e.to_string()).await
       }
     }
-
-    local_channel.close(200, "").unwrap();
-    session.close(200, "");
-  })
+  };
+  match reference {
+    Some((reference, _)) if handled => reference.delete(pg_client).await,
+    Some((reference, _)) => warn!("{:?} -> {:?} kept the payload {} in {:?} of the undelivered message",
+                                  binding.pg_channel, binding.amqp_entity, reference.id, reference.table),
+    None => {}
+  }
+  Ok(())
 }
 
+async fn delete_payload(pg_client: &Client, binding: &Binding, notification: &str){
+  if let (Some(_), Some(Ok(reference))) = (&binding.options.payload_table, parse_reference(notification)) {
+    reference.delete(pg_client).await;
+  }
+}
 
 /*
- * Finds the amqp entity type(Queue or Exchange) using two channels because currently rust-amqp hangs up when
- * doing exchange_declare and queue_declare on the same channel.
- * It does this with amqp "passive" set to true.
+ * Waits until the binding channel is notified or the poll interval passes, the notifications are only used as wake ups.
+ * Returns false once the bridge stops listening.
 */
-fn get_amq_entity_type(queue_channel: &mut Channel, exchange_channel: &mut Channel, amqp_entity: &str) -> Option<Type>{
-  let opt_queue_type = queue_channel.queue_declare(amqp_entity.clone(), true, false, false, false, false, Table::new())
-                       .map(|_| Type::Queue).ok();
-  let opt_exchange_type = exchange_channel.exchange_declare(amqp_entity, "", true, false, false, false, false, Table::new())
-                          .map(|_| Type::Exchange).ok();
-  queue_channel.close(200, "").unwrap();
-  //Somehow the exchange channel is not being closed, a solution could be to close session and reopen
-  //However when doing that some error messages(they don't seem to affect the bridge) are shown and that could be confusing for the user
-  exchange_channel.close(200, "").unwrap();
-  opt_exchange_type.or(opt_queue_type)
+async fn wait_for_wake_up(notifications: &mut UnboundedReceiver<String>, poll_interval: Duration) -> bool{
+  match tokio::time::timeout(poll_interval, notifications.recv()).await {
+    Ok(None) => false,
+    _ => {
+      // A single wake up covers all the notifications that arrived meanwhile
+      while notifications.try_recv().is_ok() {}
+      true
+    }
+  }
 }
 
-fn parse_bridge_channels(bridge_channels: &str) -> Vec<Binding>{
+pub fn parse_bridge_channels(bridge_channels: &str) -> Result<Vec<Binding>, BridgeError>{
   let mut bindings: Vec<Binding> = Vec::new();
-  let strs: Vec<Vec<&str>> = bridge_channels.split(",").map(|s| s.split(":").collect()).collect();
-  for i in 0..strs.len(){
-    bindings.push(Binding{pg_channel: strs[i][0].trim().to_string(),
-                        amqp_entity: strs[i].get(1).unwrap_or(&"").trim().to_string()});
+  let strs: Vec<Vec<&str>> = bridge_channels.split(',').map(|s| s.splitn(2, CHANNEL_SEPARATOR).collect()).collect();
+  for s in strs{
+    let entity_and_options: Vec<&str> = s.get(1).unwrap_or(&"").splitn(2, OPTIONS_SEPARATOR).collect();
+    // The exchange: or queue: prefix states the type of the amqp entity
+    let entity = entity_and_options[0].trim();
+    let (entity_type, amqp_entity) = if let Some(exchange) = entity.strip_prefix(EXCHANGE_PREFIX) {
+      (Some(Type::Exchange), exchange)
+    } else if let Some(queue) = entity.strip_prefix(QUEUE_PREFIX) {
+      (Some(Type::Queue), queue)
+    } else {
+      (None, entity)
+    };
+    bindings.push(Binding{pg_channel: s[0].trim().to_string(),
+                        amqp_entity: amqp_entity.trim().to_string(),
+                        options: parse_binding_options(entity_type, entity_and_options.get(1).unwrap_or(&""))?});
   }
-  let mut cleaned_bindings : Vec<Binding> = bindings.into_iter().filter(|x| !x.pg_channel.is_empty() && !x.amqp_entity.is_empty())
-                                          .collect();
-  if cleaned_bindings.len() == 0 {
-    panic!("No bindings(e.g. pgchannel1:queue1) specified in \"{}\"", bridge_channels)
+  let cleaned_bindings : Vec<Binding> = bindings.into_iter().filter(|x| !x.pg_channel.is_empty() && !x.amqp_entity.is_empty())
+                                      .collect();
+  if cleaned_bindings.is_empty() {
+    return Err(BridgeError::Config(format!("No bindings(e.g. pgchannel1:queue1) specified in \"{}\"", bridge_channels)));
   }
+  check_bindings(cleaned_bindings)
+}
+
+/*
+ * A channel can be bound to several amqp entities, each one gets every notification. The outbox and slot bindings
+ * take their messages from elsewhere and only use the channel to wake up, so they can't share it.
+*/
+fn check_bindings(mut cleaned_bindings: Vec<Binding>) -> Result<Vec<Binding>, BridgeError>{
   cleaned_bindings.sort();
-  cleaned_bindings.dedup_by(|a, b|{
-    let is_dup = a.pg_channel == b.pg_channel;
-    if is_dup{
-      panic!("Cannot have duplicate PostgreSQL channels.");
+  for pair in cleaned_bindings.windows(2).filter(|pair| pair[0].pg_channel == pair[1].pg_channel) {
+    if pair[0].amqp_entity == pair[1].amqp_entity {
+      return Err(BridgeError::Config(format!("Cannot have duplicate bindings, {:?} is bound to {:?} twice", pair[0].pg_channel, pair[0].amqp_entity)));
+    }
+    // The first binding to publish a referenced payload deletes it
+    if pair.iter().any(|binding| binding.options.outbox.is_some() || binding.options.slot.is_some() || binding.options.payload_table.is_some()) {
+      return Err(BridgeError::Config(format!("The outbox, slot and payload_table bindings can't share their channel, {:?} has several bindings", pair[0].pg_channel)));
+    }
+  }
+  Ok(cleaned_bindings)
+}
+
+fn parse_binding_options(entity_type: Option<Type>, options: &str) -> Result<BindingOptions, BridgeError>{
+  let mut binding_options = BindingOptions{ entity_type, ..BindingOptions::default() };
+  for option in options.split(OPTION_SEPARATOR).map(|x| x.trim()).filter(|x| !x.is_empty()){
+    let name_value: Vec<&str> = option.splitn(2, OPTION_NAME_VALUE_SEPARATOR).map(|x| x.trim()).collect();
+    match name_value[..] {
+      ["persistent"] => binding_options.delivery_mode = Some(2),
+      ["non_persistent"] => binding_options.delivery_mode = Some(1),
+      ["key", key] if !key.is_empty() => binding_options.routing_key = Some(key.to_string()),
+      ["prefix", prefix] if !prefix.is_empty() => binding_options.routing_key_prefix = Some(prefix.to_string()),
+      ["kind", "direct"] => binding_options.exchange_kind = Some(Kind::Direct),
+      ["kind", "fanout"] => binding_options.exchange_kind = Some(Kind::Fanout),
+      ["kind", "topic"] => binding_options.exchange_kind = Some(Kind::Topic),
+      ["kind", "headers"] => binding_options.exchange_kind = Some(Kind::Headers),
+      ["format", "pipe"] => binding_options.format = PayloadFormat::Pipe,
+      ["format", "json"] => binding_options.format = PayloadFormat::Json,
+      ["confirm"] => binding_options.confirm = true,
+      ["retries", retries] =>
+        binding_options.retries = retries.parse().map_err(|_| BridgeError::Config(format!("Binding option retries must be a number, got \"{}\"", retries)))?,
+      ["fallback", fallback] if !fallback.is_empty() => binding_options.fallback = Some(fallback.to_string()),
+      ["fallback_table", fallback_table] if !fallback_table.is_empty() => binding_options.fallback_table = Some(fallback_table.to_string()),
+      ["outbox", outbox] if !outbox.is_empty() => binding_options.outbox = Some(outbox.to_string()),
+      ["slot", slot] if !slot.is_empty() => binding_options.slot = Some(slot.to_string()),
+      ["payload_table", payload_table] if !payload_table.is_empty() => binding_options.payload_table = Some(payload_table.to_string()),
+      ["publication", publication] if !publication.is_empty() => binding_options.publication = Some(publication.to_string()),
+      ["on_failure", "stop"] => binding_options.on_failure = FailurePolicy::Stop,
+      ["on_failure", "skip"] => binding_options.on_failure = FailurePolicy::Skip,
+      ["on_failure", "retry"] => binding_options.on_failure = FailurePolicy::Retry,
+      ["poll_interval", poll_interval] =>
+        binding_options.poll_interval = poll_interval.parse().map_err(|_| BridgeError::Config(format!("Binding option poll_interval must be a number, got \"{}\"", poll_interval)))?,
+      [name, value] if Properties::is_property(name) =>
+        binding_options.properties.set(name, value).map_err(|e| BridgeError::Config(format!("Invalid binding option \"{}\": {}", option, e)))?,
+      _ => return Err(BridgeError::Config(format!("Unknown binding option \"{}\"", option)))
     }
-    is_dup
-  });
-  cleaned_bindings
+  }
+  binding_options.finish()
 }
 
-fn parse_notification(payload: &str) -> (&str, &str, Option<Table>){
+fn parse_notification(payload: &str) -> (&str, &str, Option<FieldTable>){
   let v: Vec<&str> = payload.splitn(3, SEPARATOR).map(|x| x.trim()).collect();
   match v.len() {
     3 => {
         let components: Vec<&str> = v[1].split(HEADERS_SEPARATOR).map(|x| x.trim()).collect();
-        let mut headers = Table::new();
+        let mut headers = FieldTable::default();
         for c in components {
             let array: Vec<&str> = c.splitn(2, HEADER_NAME_VALUE_SEPARATOR).map(|x| x.trim()).collect();
             if let [name, values] = array[..] {
                 let values: Vec<&str> = values.split( HEADER_VALUES_SEPARATOR).map(|x| x.trim()).collect();
                 let mut fields = vec![];
                 for v in values {
-                    fields.push(TableEntry::LongString(v.to_owned()));
+                    fields.push(AMQPValue::LongString(v.into()));
                 }
-                headers.insert(name.to_owned(), TableEntry::FieldArray(fields));
+                headers.insert(name.into(), AMQPValue::FieldArray(fields.into()));
             }
         }
         (v[0], v[2], Some(headers))
@@ -206,23 +414,6 @@ fn parse_notification(payload: &str) -> (&str, &str, Option<Table>){
   }
 }
 
-pub fn wait_for_amqp_session(amqp_uri: &str, pg_channel: &str) -> Session {
-  println!("Attempting to obtain connection on AMQP server for {} channel..", pg_channel);
-  let mut s = Session::open_url(amqp_uri);
-  let mut i = 1;
-  while let Err(e)  = s {
-    println!("{:?}", e);
-    let time = Duration::from_secs(i);
-    println!("Retrying the AMQP connection for {} channel in {:?} seconds..", pg_channel, time.as_secs());
-    thread::sleep(time);
-    s = Session::open_url(amqp_uri);
-    i *= 2;
-    if i > 32 { i = 1 };
-  };
-  println!("Connection to AMQP server for {} channel successful", pg_channel);
-  s.unwrap()
-}
-
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -235,47 +426,123 @@ mod tests {
     assert!(("", "A message", None) == parse_notification("A message"));
     assert!(("", "", None) == parse_notification(""));
     assert!(("mý_kéý", "A mésságé", None) == parse_notification("mý_kéý|A mésságé"));
-    assert_eq!(("my_key", "A message", Some(hashmap!{
-      "Content-Type".to_owned() => TableEntry::FieldArray(vec![
-        TableEntry::LongString("application/json".to_owned()),
-        TableEntry::LongString("application/octet-stream".to_owned()),
-      ]),
-      "X-My-Header".to_owned() => TableEntry::FieldArray(vec![
-        TableEntry::LongString("my-value".to_owned()),
-      ])
-    })), parse_notification("my_key|Content-Type: application/json, application/octet-stream; X-My-Header: my-value|A message"));
+    assert_eq!(("my_key", "A message", Some(FieldTable::from(btreemap!{
+      "Content-Type".into() => AMQPValue::FieldArray(vec![
+        AMQPValue::LongString("application/json".into()),
+        AMQPValue::LongString("application/octet-stream".into()),
+      ].into()),
+      "X-My-Header".into() => AMQPValue::FieldArray(vec![
+        AMQPValue::LongString("my-value".into()),
+      ].into())
+    }))), parse_notification("my_key|Content-Type: application/json, application/octet-stream; X-My-Header: my-value|A message"));
   }
 
   #[test]
   fn parse_bridge_channels_works() {
-    assert!(vec![Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string()}]
-            == parse_bridge_channels("pgchannel1:exchange1"));
+    assert!(vec![Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(), options: BindingOptions::default()}]
+            == parse_bridge_channels("pgchannel1:exchange1").unwrap());
+    assert!(vec![
+              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(), options: BindingOptions::default()},
+              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "exchange2".to_string(), options: BindingOptions::default()}
+            ] == parse_bridge_channels("pgchannel1:exchange1,pgchannel2:exchange2").unwrap());
+    assert!(vec![
+              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(), options: BindingOptions::default()},
+              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "exchange2".to_string(), options: BindingOptions::default()},
+              Binding{pg_channel: "pgchannel3".to_string(), amqp_entity: "exchange3".to_string(), options: BindingOptions::default()}
+            ] == parse_bridge_channels(" pgchannel1 : exchange1 , pgchannel2 : exchange2 , pgchannel3 : exchange3, ").unwrap());
+  }
+
+  #[test]
+  fn parse_bridge_channels_with_options_works() {
+    assert!(vec![Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(),
+                         options: BindingOptions{confirm: true, ..BindingOptions::default()}}]
+            == parse_bridge_channels("pgchannel1:exchange1?confirm").unwrap());
+    assert!(vec![Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(),
+                         options: BindingOptions{payload_table: Some("rabbitmq.payloads".to_string()), ..BindingOptions::default()}}]
+            == parse_bridge_channels("pgchannel1:exchange1?payload_table=rabbitmq.payloads").unwrap());
     assert!(vec![
-              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string()},
-              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "exchange2".to_string()}
-            ] == parse_bridge_channels("pgchannel1:exchange1,pgchannel2:exchange2"));
+              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(),
+                      options: BindingOptions{confirm: true, retries: 5, fallback: Some("failed".to_string()), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "queue2".to_string(), options: BindingOptions::default()},
+              Binding{pg_channel: "pgchannel3".to_string(), amqp_entity: "exchange3".to_string(),
+                      options: BindingOptions{confirm: true, fallback_table: Some("bridge.failed".to_string()), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel4".to_string(), amqp_entity: "queue4".to_string(),
+                      options: BindingOptions{confirm: true, outbox: Some("bridge.outbox".to_string()), poll_interval: 1, ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel5".to_string(), amqp_entity: "exchange5".to_string(),
+                      options: BindingOptions{properties: Properties{content_type: Some("application/json".to_string()), ..Properties::default()}, confirm: true,
+                                              slot: Some("bridge_slot".to_string()), publication: Some("bridge_pub".to_string()), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel6".to_string(), amqp_entity: "exchange6".to_string(),
+                      options: BindingOptions{format: PayloadFormat::Json, ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel7".to_string(), amqp_entity: "exchange7".to_string(),
+                      options: BindingOptions{properties: Properties{content_type: Some("application/json".to_string()), app_id: Some("billing".to_string()),
+                                                                     priority: Some(5), ..Properties::default()}, ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel8".to_string(), amqp_entity: "exchange8".to_string(),
+                      options: BindingOptions{delivery_mode: Some(2), routing_key: Some("audit.default".to_string()),
+                                              routing_key_prefix: Some("tenant1.".to_string()), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel9".to_string(), amqp_entity: "queue9".to_string(),
+                      options: BindingOptions{delivery_mode: Some(1), ..BindingOptions::default()}}
+            ] == parse_bridge_channels(" pgchannel1 : exchange1 ? confirm & retries=5 & fallback=failed , pgchannel2 : queue2?, pgchannel3:exchange3?confirm&fallback_table=bridge.failed, pgchannel4:queue4?outbox=bridge.outbox&poll_interval=1, pgchannel5:exchange5?slot=bridge_slot&publication=bridge_pub, pgchannel6:exchange6?format=json, pgchannel7:exchange7?content_type=application/json&app_id=billing&priority=5, pgchannel8:exchange8?persistent&key=audit.default&prefix=tenant1., pgchannel9:queue9?non_persistent").unwrap());
+  }
+
+  #[test]
+  fn parse_bridge_channels_with_entity_type_works() {
     assert!(vec![
-              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string()},
-              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "exchange2".to_string()},
-              Binding{pg_channel: "pgchannel3".to_string(), amqp_entity: "exchange3".to_string()}
-            ] == parse_bridge_channels(" pgchannel1 : exchange1 , pgchannel2 : exchange2 , pgchannel3 : exchange3, "));
+              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "exchange1".to_string(),
+                      options: BindingOptions{entity_type: Some(Type::Exchange), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "queue2".to_string(),
+                      options: BindingOptions{entity_type: Some(Type::Queue), confirm: true, ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel3".to_string(), amqp_entity: "exchange3".to_string(),
+                      options: BindingOptions{entity_type: Some(Type::Exchange), exchange_kind: Some(Kind::Topic), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel4".to_string(), amqp_entity: "exchange4".to_string(),
+                      options: BindingOptions{entity_type: Some(Type::Exchange), exchange_kind: Some(Kind::Fanout), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel5".to_string(), amqp_entity: "exchange".to_string(), options: BindingOptions::default()}
+            ] == parse_bridge_channels("pgchannel1:exchange:exchange1, pgchannel2: queue: queue2?confirm, pgchannel3:exchange:exchange3?kind=topic, pgchannel4:exchange4?kind=fanout, pgchannel5:exchange").unwrap());
+  }
+
+  #[test]
+  fn parse_bridge_channels_fails_if_no_pg_channel_and_exchange_specified() {
+    assert!(parse_bridge_channels("   ").is_err());
+    assert!(parse_bridge_channels(":").is_err());
+    assert!(parse_bridge_channels("pgchannel1").is_err());
+    assert!(parse_bridge_channels(":exchange1").is_err());
+    assert!(parse_bridge_channels("pgchannel1:").is_err());
+    assert!(parse_bridge_channels("pgchannel1, pgchannel1:, :exchange3,,").is_err());
   }
 
-  use std::panic::catch_unwind;
+  #[test]
+  fn parse_bridge_channels_with_several_targets_works() {
+    assert!(vec![
+              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "audit_queue".to_string(),
+                      options: BindingOptions{delivery_mode: Some(2), ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel1".to_string(), amqp_entity: "events_exchange".to_string(),
+                      options: BindingOptions{routing_key: Some("events.default".to_string()), on_failure: FailurePolicy::Retry, ..BindingOptions::default()}},
+              Binding{pg_channel: "pgchannel2".to_string(), amqp_entity: "queue2".to_string(),
+                      options: BindingOptions{on_failure: FailurePolicy::Skip, ..BindingOptions::default()}}
+            ] == parse_bridge_channels("pgchannel1:events_exchange?key=events.default&on_failure=retry, pgchannel1:audit_queue?persistent, pgchannel2:queue2?on_failure=skip").unwrap());
+  }
 
   #[test]
-  fn parse_bridge_channels_panics_if_no_pg_channel_and_exchange_specified() {
-    assert!(catch_unwind(|| parse_bridge_channels("   ")).is_err());
-    assert!(catch_unwind(|| parse_bridge_channels(":")).is_err());
-    assert!(catch_unwind(|| parse_bridge_channels("pgchannel1")).is_err());
-    assert!(catch_unwind(|| parse_bridge_channels(":exchange1")).is_err());
-    assert!(catch_unwind(|| parse_bridge_channels("pgchannel1:")).is_err());
-    assert!(catch_unwind(|| parse_bridge_channels("pgchannel1, pgchannel1:, :exchange3,,")).is_err());
+  fn parse_bridge_channels_fails_if_duplicate_binding() {
+    assert!(parse_bridge_channels("pgchannel1,pgchannel1:exchange2,pgchannel1:exchange2?confirm,").is_err());
+    assert!(parse_bridge_channels("pgchannel2, pgchannel2").is_err());
+    assert!(parse_bridge_channels("pgchannel3:queue3,pgchannel3:queue4?outbox=bridge.outbox").is_err());
+    assert!(parse_bridge_channels("pgchannel3:queue3,pgchannel3:queue4?payload_table=rabbitmq.payloads").is_err());
+    assert!(parse_bridge_channels("pgchannel3:queue3?slot=bridge_slot,pgchannel3:queue4").is_err());
   }
 
   #[test]
-  fn parse_bridge_channels_panics_if_duplicate_pg_channel() {
-    assert!(catch_unwind(|| parse_bridge_channels("pgchannel1,pgchannel1:exchange2,pgchannel1:exchange3,")).is_err());
-    assert!(catch_unwind(|| parse_bridge_channels("pgchannel2, pgchannel2")).is_err());
+  fn parse_bridge_channels_fails_if_invalid_option() {
+    assert!(parse_bridge_channels("pgchannel1:exchange1?confirmed").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?confirm&retries=many").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?fallback=").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?fallback_table").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?outbox=bridge.outbox&slot=bridge_slot").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?outbox=bridge.outbox&payload_table=rabbitmq.payloads").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?format=xml").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?priority=high").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?kind=broadcast").is_err());
+    assert!(parse_bridge_channels("pgchannel1:exchange1?on_failure=ignore").is_err());
+    assert!(parse_bridge_channels("pgchannel1:queue:queue1?kind=topic").is_err());
+    assert!(parse_bridge_channels("pgchannel1:queue:").is_err());
   }
 }
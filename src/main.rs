@@ -1,72 +1,200 @@
 extern crate env_logger;
 extern crate pg_amqp_bridge as bridge;
-extern crate r2d2;
-extern crate r2d2_postgres;
+extern crate signal_hook;
 
 use std::env;
 use std::fs;
+use std::process;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use r2d2::{Pool, ManageConnection};
-use r2d2_postgres::{PostgresConnectionManager, postgres::NoTls};
+use bridge::{AmqpTls, Binding, Bridge, BridgeHandle, Consumer, NodeOrder, PgTls, Relay, Rpc, SpoolOptions, Topology, parse_bridge_consumers, parse_bridge_relays, parse_bridge_rpcs};
+use bridge::error::BridgeError;
+use bridge::config::{ConfigFile, read_config_file, parse_amqp_uris, parse_delivery_mode, parse_node_order, parse_spool_overflow};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+// Time the publishers get after the shutdown timeout to send the pending notifications to the failure path
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
 
 #[derive(Debug, Clone)]
 struct Config {
   postgresql_uri: String,
-  amqp_uri: String,
-  bridge_channels: String,
+  pg_tls: PgTls,
+  amqp_uris: Vec<String>,
+  amqp_node_order: NodeOrder,
+  amqp_tls: AmqpTls,
+  // Only in the config file
+  topology: Topology,
+  bindings: Vec<Binding>,
+  consumers: Vec<Consumer>,
+  relays: Vec<Relay>,
+  rpcs: Vec<Rpc>,
   delivery_mode: u8,
+  shutdown_timeout: Duration,
+  spool: Option<SpoolOptions>,
 }
 
 impl Config {
+  // The environment variables override the CONFIG_FILE settings
   fn new() -> Config {
+    let config_file = match env::var("CONFIG_FILE") {
+      Ok(path) => read_config_file(&path).unwrap_or_else(|e| exit_with(e)),
+      Err(_e) => ConfigFile::default()
+    };
+    let (spool_max_bytes, spool_overflow) = (config_file.spool_max_bytes, config_file.spool_overflow);
     Config {
-      postgresql_uri: read_env_with_secret("POSTGRESQL_URI"),
-      amqp_uri: read_env_with_secret("AMQP_URI"),
-      bridge_channels: env::var("BRIDGE_CHANNELS").expect("BRIDGE_CHANNELS environment variable must be defined"),
+      postgresql_uri: read_env_with_secret("POSTGRESQL_URI", config_file.postgresql_uri),
+      pg_tls: PgTls{
+        root_cert: read_optional_env_with_secret("POSTGRESQL_SSL_ROOT_CERT", config_file.postgresql_ssl_root_cert),
+        client_cert: read_optional_env_with_secret("POSTGRESQL_SSL_CERT", config_file.postgresql_ssl_cert),
+        client_key: read_optional_env_with_secret("POSTGRESQL_SSL_KEY", config_file.postgresql_ssl_key)
+      },
+      amqp_uris: parse_amqp_uris(&read_env_with_secret("AMQP_URI", config_file.amqp_uri)),
+      amqp_node_order:
+        match env::var("AMQP_URI_ORDER") {
+          Ok(order) => parse_node_order(&order)
+            .expect("AMQP_URI_ORDER environment variable can only be ordered or shuffled"),
+          Err(_e) => config_file.amqp_uri_order.unwrap_or(NodeOrder::Ordered)
+        },
+      amqp_tls: AmqpTls{
+        root_cert: read_optional_env_with_secret("AMQP_SSL_ROOT_CERT", config_file.amqp_ssl_root_cert),
+        client_cert: read_optional_env_with_secret("AMQP_SSL_CERT", config_file.amqp_ssl_cert),
+        client_key: read_optional_env_with_secret("AMQP_SSL_KEY", config_file.amqp_ssl_key),
+        server_name: env::var("AMQP_SSL_SERVER_NAME").ok().or(config_file.amqp_ssl_server_name),
+        verify:
+          match env::var("AMQP_SSL_VERIFY") {
+            Ok(verify) => verify.parse().expect("AMQP_SSL_VERIFY environment variable can only be true or false"),
+            Err(_e) => config_file.amqp_ssl_verify.unwrap_or(true)
+          }
+      },
+      topology: config_file.topology.unwrap_or_default(),
+      bindings:
+        match env::var("BRIDGE_CHANNELS") {
+          Ok(bridge_channels) => bridge::parse_bridge_channels(&bridge_channels).unwrap_or_else(|e| exit_with(e)),
+          Err(_e) if !config_file.bindings.is_empty() => config_file.bindings,
+          // The bridge can only consume, relay or take RPC replies
+          Err(_e) if env::var("BRIDGE_CONSUMERS").is_ok() || !config_file.consumers.is_empty() ||
+                     env::var("BRIDGE_RELAYS").is_ok() || !config_file.relays.is_empty() ||
+                     env::var("BRIDGE_RPC").is_ok() || !config_file.rpcs.is_empty() => Vec::new(),
+          Err(_e) => panic!("BRIDGE_CHANNELS environment variable or the bindings of CONFIG_FILE must be defined")
+        },
+      consumers:
+        match env::var("BRIDGE_CONSUMERS") {
+          Ok(bridge_consumers) => parse_bridge_consumers(&bridge_consumers).unwrap_or_else(|e| exit_with(e)),
+          Err(_e) => config_file.consumers
+        },
+      relays:
+        match env::var("BRIDGE_RELAYS") {
+          Ok(bridge_relays) => parse_bridge_relays(&bridge_relays).unwrap_or_else(|e| exit_with(e)),
+          Err(_e) => config_file.relays
+        },
+      rpcs:
+        match env::var("BRIDGE_RPC") {
+          Ok(bridge_rpcs) => parse_bridge_rpcs(&bridge_rpcs).unwrap_or_else(|e| exit_with(e)),
+          Err(_e) => config_file.rpcs
+        },
       delivery_mode:
-        match env::var("DELIVERY_MODE").ok().as_ref().map(String::as_ref){
-          None => 1,
-          Some("NON-PERSISTENT") => 1,
-          Some("PERSISTENT") => 2,
-          Some(_) => panic!("DELIVERY_MODE environment variable can only be PERSISTENT or NON-PERSISTENT")
+        match env::var("DELIVERY_MODE") {
+          Ok(delivery_mode) => parse_delivery_mode(&delivery_mode)
+            .expect("DELIVERY_MODE environment variable can only be PERSISTENT or NON-PERSISTENT"),
+          Err(_e) => config_file.delivery_mode.unwrap_or(1)
+        },
+      shutdown_timeout:
+        match env::var("SHUTDOWN_TIMEOUT") {
+          Ok(timeout) => Duration::from_secs(timeout.parse()
+            .expect("SHUTDOWN_TIMEOUT environment variable must be a number of seconds")),
+          Err(_e) => Duration::from_secs(config_file.shutdown_timeout.unwrap_or(10))
+        },
+      spool: env::var("SPOOL_DIR").ok().or(config_file.spool_dir).map(|dir| {
+        let defaults = SpoolOptions::new(&dir);
+        SpoolOptions{
+          max_bytes:
+            match env::var("SPOOL_MAX_BYTES") {
+              Ok(max_bytes) => max_bytes.parse().expect("SPOOL_MAX_BYTES environment variable must be a number of bytes"),
+              Err(_e) => spool_max_bytes.unwrap_or(defaults.max_bytes)
+            },
+          overflow:
+            match env::var("SPOOL_OVERFLOW") {
+              Ok(overflow) => parse_spool_overflow(&overflow)
+                .expect("SPOOL_OVERFLOW environment variable can only be reject or drop_oldest"),
+              Err(_e) => spool_overflow.unwrap_or(defaults.overflow)
+            },
+          ..defaults
         }
+      })
     }
   }
 }
 
-fn read_env_with_secret(key: &str) -> String {
-  return match env::var(format!("{}_FILE", key)) {
-    Ok(val) => fs::read_to_string(val.clone()).expect(format!("Something went wrong reading {}", val).as_ref()),
-    Err(_e) => env::var(key).expect(format!("{} environment variable must be defined", key).as_ref()),
+#[allow(clippy::expect_fun_call)]
+fn read_env_with_secret(key: &str, config_file_value: Option<String>) -> String {
+  read_optional_env_with_secret(key, config_file_value).expect(format!("{} environment variable must be defined", key).as_ref())
+}
+
+#[allow(clippy::expect_fun_call)]
+fn read_optional_env_with_secret(key: &str, config_file_value: Option<String>) -> Option<String> {
+  match env::var(format!("{}_FILE", key)) {
+    Ok(val) => Some(fs::read_to_string(val.clone()).expect(format!("Something went wrong reading {}", val).as_ref())),
+    Err(_e) => env::var(key).ok().or(config_file_value),
   }
 }
 
 fn main() {
   env_logger::init();
   let config = Config::new();
-
-  loop {
-    let pool = wait_for_pg_connection(&config.postgresql_uri);
-    // This functions spawns threads for each pg channel and waits for the threads to finish,
-    // that only occurs when the threads die due to a pg connection error
-    // and so if that happens the pg connection is retried and the bridge is started again.
-    bridge::start(pool, &config.amqp_uri, &config.bridge_channels, &config.delivery_mode);
+  // The bridge connects and listens again by itself when the pg connection is lost, it only stops on shutdown
+  // or when a binding can't start
+  let mut builder = Bridge::builder()
+    .postgresql_uri(&config.postgresql_uri)
+    .postgresql_tls(config.pg_tls)
+    .amqp_uris(config.amqp_uris)
+    .amqp_node_order(config.amqp_node_order)
+    .amqp_tls(config.amqp_tls)
+    .topology(config.topology)
+    .delivery_mode(config.delivery_mode)
+    .bindings(config.bindings)
+    .consumers(config.consumers)
+    .relays(config.relays)
+    .rpcs(config.rpcs)
+    .shutdown_timeout(config.shutdown_timeout);
+  if let Some(spool) = config.spool {
+    builder = builder.spool(spool);
   }
+  let bridge = builder.start().unwrap_or_else(|e| exit_with(e));
+  let bridge = Arc::new(bridge);
+  handle_signals(bridge.clone(), config.shutdown_timeout);
+
+  bridge.join().unwrap_or_else(|e| exit_with(e));
+  println!("Shutdown complete");
+}
+
+fn exit_with(e: BridgeError) -> ! {
+  eprintln!("{}", e);
+  process::exit(1);
 }
 
-fn wait_for_pg_connection(pg_uri: &String) -> Pool<PostgresConnectionManager<NoTls>> {
-  println!("Attempting to connect to PostgreSQL..");
-  let conn = PostgresConnectionManager::new(pg_uri.to_owned().parse().unwrap(), NoTls);
-  let mut i = 1;
-  while let Err(e) = conn.connect() {
-    println!("{:?}", e);
-    let time = Duration::from_secs(i);
-    println!("Retrying the PostgreSQL connection in {:?} seconds..", time.as_secs());
-    thread::sleep(time);
-    i *= 2;
-    if i > 32 { i = 1 };
-  };
-  println!("Connection to PostgreSQL successful");
-  Pool::new(conn).unwrap()
+/*
+ * The first SIGTERM or SIGINT starts a graceful shutdown, the process exits anyway when a second signal arrives
+ * or when the shutdown takes longer than the timeout plus the grace period.
+*/
+fn handle_signals(bridge: Arc<BridgeHandle>, timeout: Duration) {
+  let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Could not register the signal handlers");
+  thread::spawn(move || {
+    let mut shutting_down = false;
+    for signal in signals.forever() {
+      if shutting_down {
+        println!("Received signal {} during the shutdown, exiting..", signal);
+        process::exit(1);
+      }
+      println!("Received signal {}, shutting down in {:?} seconds..", signal, timeout.as_secs());
+      shutting_down = true;
+      bridge.stop();
+      thread::spawn(move || {
+        thread::sleep(timeout + SHUTDOWN_GRACE_PERIOD);
+        println!("The shutdown timed out, exiting..");
+        process::exit(1);
+      });
+    }
+  });
 }
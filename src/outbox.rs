@@ -0,0 +1,88 @@
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_postgres::Client;
+
+use super::{Binding, PgClients, wait_for_wake_up};
+use super::shutdown::Shutdown;
+use super::publisher::Publisher;
+
+const OUTBOX_BATCH_SIZE: usize = 100;
+
+/*
+ * The outbox is drained when the bridge starts, whenever the binding channel is notified and every poll interval,
+ * so the rows inserted while the bridge was down or reconnecting are also published. On shutdown the pending rows
+ * are left for the next start.
+ * The drains run in transactions, so the binding has its own connection instead of the shared one.
+*/
+pub async fn relay_outbox(pg: &PgClients, publisher: &mut Publisher, binding: &Binding, outbox: &str, notifications: &mut UnboundedReceiver<String>, shutdown: &Shutdown){
+  let poll_interval = Duration::from_secs(binding.options.poll_interval);
+  let mut pg_client: Option<Client> = None;
+  loop {
+    if pg_client.as_ref().is_none_or(Client::is_closed) {
+      pg_client = match pg.connect().await {
+        Ok(pg_connection) => Some(pg_connection.client),
+        Err(e) => {
+          error!("{:?} -> {:?} could not connect to drain the outbox {:?}: {}", binding.pg_channel, binding.amqp_entity, outbox, e);
+          None
+        }
+      };
+    }
+    if let Some(ref mut pg_client) = pg_client {
+      if let Err(e) = drain_outbox(pg_client, publisher, outbox, &binding.pg_channel, shutdown).await {
+        error!("{:?} -> {:?} could not drain the outbox {:?}: {}", binding.pg_channel, binding.amqp_entity, outbox, e);
+      }
+    }
+    if !wait_for_wake_up(notifications, poll_interval).await {
+      return;
+    }
+  }
+}
+
+/*
+ * Publishes the outbox rows of the channel oldest first, with the same format as the notifications payload.
+ * A row is deleted in the transaction that locked it and only after the broker confirmed the message(or a fallback
+ * took it), the rows that couldn't be published are left for the next drain.
+ * SKIP LOCKED lets many bridges work on the same table.
+*/
+async fn drain_outbox(pg_client: &mut Client, publisher: &mut Publisher, outbox: &str, pg_channel: &str, shutdown: &Shutdown) -> Result<(), tokio_postgres::Error>{
+  let select_command = format!("SELECT id, payload FROM {} WHERE channel = $1 ORDER BY id LIMIT {} FOR UPDATE SKIP LOCKED",
+                               outbox, OUTBOX_BATCH_SIZE);
+  let delete_command = format!("DELETE FROM {} WHERE id = $1", outbox);
+  loop {
+    let transaction = pg_client.transaction().await?;
+    let rows = transaction.query(select_command.as_str(), &[&pg_channel]).await?;
+    let mut published = 0;
+    for row in &rows {
+      if shutdown.is_overdue() {
+        break;
+      }
+      let id: i64 = row.get(0);
+      let message = match publisher.message_from_payload(row.get(1)) {
+        Ok(message) => message,
+        Err(e) => {
+          // Invalid rows would block the outbox forever
+          publisher.send_invalid_payload_to_fallback(row.get(1), &e).await;
+          transaction.execute(delete_command.as_str(), &[&id]).await?;
+          published += 1;
+          continue;
+        }
+      };
+      match publisher.deliver(&message).await {
+        Ok(delivery) if delivery.is_handled() => {
+          transaction.execute(delete_command.as_str(), &[&id]).await?;
+          published += 1;
+        },
+        // Nacked or returned without a fallback that took it
+        Ok(_) => {},
+        Err(e) => {
+          error!("{:?}", e);
+          break;
+        }
+      }
+    }
+    transaction.commit().await?;
+    if published < OUTBOX_BATCH_SIZE || shutdown.is_requested() {
+      return Ok(());
+    }
+  }
+}
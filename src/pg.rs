@@ -0,0 +1,176 @@
+use postgres_native_tls::MakeTlsConnector;
+use std::future::{self, Future};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_postgres::{AsyncMessage, Client, Config, Connection, Notification, Socket};
+use tokio_postgres::config::SslMode as PgSslMode;
+use tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+
+use super::error::BridgeError;
+use super::tls;
+
+/*
+ * A PostgreSQL connection driven in the background, the notifications of its LISTEN commands come through the receiver
+ * which is closed once the connection is lost.
+*/
+pub struct PgConnection{
+  pub client: Client,
+  pub notifications: UnboundedReceiver<Notification>
+}
+
+impl PgConnection {
+  pub fn new<S, T>(client: Client, mut connection: Connection<S, T>) -> PgConnection
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    let (sender, notifications) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      loop {
+        match future::poll_fn(|cx| connection.poll_message(cx)).await {
+          Some(Ok(AsyncMessage::Notification(notification))) => {
+            // The client can still be in use when nobody takes the notifications
+            let _ = sender.send(notification);
+          },
+          Some(Ok(AsyncMessage::Notice(notice))) => info!("{}", notice),
+          Some(Ok(_)) => {},
+          Some(Err(e)) => {
+            error!("{:?}", e);
+            return;
+          },
+          None => return
+        }
+      }
+    });
+    PgConnection{ client, notifications }
+  }
+}
+
+pub type PgConnect<'a> = Pin<Box<dyn Future<Output = Result<PgConnection, BridgeError>> + Send + 'a>>;
+
+/*
+ * Gives the bridge its PostgreSQL connections, the listener keeps one that the bindings share and the outbox bindings
+ * take another one for their transactions.
+*/
+pub trait PgClientFactory: Send + Sync {
+  fn connect(&self) -> PgConnect<'_>;
+}
+
+// Connects with the given config and TLS connector, e.g. NoTls
+pub struct PgConnector<T>{
+  config: Config,
+  tls: T
+}
+
+impl<T> PgConnector<T> {
+  pub fn new(config: Config, tls: T) -> PgConnector<T> {
+    PgConnector{ config, tls }
+  }
+}
+
+impl<T> PgClientFactory for PgConnector<T>
+where
+  T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+  T::Stream: Send,
+  T::TlsConnect: Send,
+  <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+  fn connect(&self) -> PgConnect<'_> {
+    Box::pin(async move {
+      let (client, connection) = self.config.connect(self.tls.clone()).await?;
+      Ok(PgConnection::new(client, connection))
+    })
+  }
+}
+
+// The sslmode of the PostgreSQL URI, as in libpq
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SslMode {
+  Disable,
+  Prefer,
+  Require,
+  VerifyCa,
+  VerifyFull
+}
+
+/*
+ * Certificates and key of the PostgreSQL connection, in PEM and the key in PKCS#8. As in libpq, `sslmode=require`
+ * only verifies the server certificate when there's a root certificate.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PgTls{
+  pub root_cert: Option<String>,
+  pub client_cert: Option<String>,
+  pub client_key: Option<String>
+}
+
+// Connects as the URI sslmode says, with the given certificates
+pub fn tls_connector(postgresql_uri: &str, tls: &PgTls) -> Result<PgConnector<MakeTlsConnector>, BridgeError> {
+  let (postgresql_uri, ssl_mode) = take_ssl_mode(postgresql_uri);
+  let mut config: Config = postgresql_uri.parse().map_err(|e| BridgeError::Config(format!("Invalid PostgreSQL URI: {}", e)))?;
+  config.ssl_mode(match ssl_mode {
+    SslMode::Disable => PgSslMode::Disable,
+    SslMode::Prefer => PgSslMode::Prefer,
+    _ => PgSslMode::Require
+  });
+
+  let mut builder = tls::connector_builder("PostgreSQL", tls.root_cert.as_deref(), tls.client_cert.as_deref(), tls.client_key.as_deref())?;
+  let verify_cert = match ssl_mode {
+    SslMode::VerifyCa | SslMode::VerifyFull => true,
+    SslMode::Require => tls.root_cert.is_some(),
+    SslMode::Disable | SslMode::Prefer => false
+  };
+  builder.danger_accept_invalid_certs(!verify_cert);
+  builder.danger_accept_invalid_hostnames(ssl_mode != SslMode::VerifyFull);
+  Ok(PgConnector::new(config, MakeTlsConnector::new(tls::build("PostgreSQL", &builder)?)))
+}
+
+/*
+ * tokio-postgres only knows the disable, prefer and require modes, the verify ones are given to it as require.
+ * The URI can be a URL or key=value pairs, without sslmode it's prefer.
+*/
+fn take_ssl_mode(postgresql_uri: &str) -> (String, SslMode) {
+  let start = match postgresql_uri.find("sslmode=") {
+    Some(i) => i + "sslmode=".len(),
+    None => return (postgresql_uri.to_string(), SslMode::Prefer)
+  };
+  let end = postgresql_uri[start..].find(|c: char| c == '&' || c.is_whitespace()).map_or(postgresql_uri.len(), |i| start + i);
+  let ssl_mode = match &postgresql_uri[start..end] {
+    "disable" => SslMode::Disable,
+    "require" => SslMode::Require,
+    "verify-ca" => SslMode::VerifyCa,
+    "verify-full" => SslMode::VerifyFull,
+    // tokio-postgres fails on the invalid ones
+    _ => SslMode::Prefer
+  };
+  match ssl_mode {
+    SslMode::VerifyCa | SslMode::VerifyFull =>
+      (format!("{}require{}", &postgresql_uri[..start], &postgresql_uri[end..]), ssl_mode),
+    _ => (postgresql_uri.to_string(), ssl_mode)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn take_ssl_mode_works() {
+    assert_eq!(("postgres://postgres@localhost".to_string(), SslMode::Prefer), take_ssl_mode("postgres://postgres@localhost"));
+    assert_eq!(("postgres://postgres@localhost?sslmode=disable".to_string(), SslMode::Disable),
+               take_ssl_mode("postgres://postgres@localhost?sslmode=disable"));
+    assert_eq!(("postgres://postgres@localhost?sslmode=require&application_name=bridge".to_string(), SslMode::VerifyCa),
+               take_ssl_mode("postgres://postgres@localhost?sslmode=verify-ca&application_name=bridge"));
+    assert_eq!(("host=localhost sslmode=require user=postgres".to_string(), SslMode::VerifyFull),
+               take_ssl_mode("host=localhost sslmode=verify-full user=postgres"));
+  }
+
+  #[test]
+  fn tls_connector_fails_on_invalid_settings() {
+    assert!(tls_connector("postgres://postgres@localhost?sslmode=verify-full", &PgTls::default()).is_ok());
+    assert!(tls_connector("postgres://postgres@localhost?sslmode=sometimes", &PgTls::default()).is_err());
+    assert!(tls_connector("postgres://postgres@localhost", &PgTls{ root_cert: Some("not a certificate".to_string()), ..PgTls::default() }).is_err());
+    assert!(tls_connector("postgres://postgres@localhost", &PgTls{ client_key: Some("a key".to_string()), ..PgTls::default() }).is_err());
+  }
+}
@@ -0,0 +1,163 @@
+use lapin::BasicProperties;
+use lapin::types::{AMQPValue, FieldTable, ShortString};
+
+// Prefix of the pipe format headers that set a property instead of being sent as headers, e.g. amqp_reply_to: my_queue
+const PROPERTY_HEADER_PREFIX: &str = "amqp_";
+
+const DEFAULT_CONTENT_TYPE: &str = "text";
+
+/*
+ * AMQP basic properties that the bindings set by default and the notifications can override.
+ * The headers and delivery mode are set apart.
+*/
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Default)]
+pub struct Properties{
+  pub content_type: Option<String>,
+  pub content_encoding: Option<String>,
+  pub priority: Option<u8>,
+  pub correlation_id: Option<String>,
+  pub reply_to: Option<String>,
+  pub expiration: Option<String>,
+  pub message_id: Option<String>,
+  pub timestamp: Option<u64>,
+  pub message_type: Option<String>,
+  pub user_id: Option<String>,
+  pub app_id: Option<String>
+}
+
+impl Properties {
+  pub fn is_property(name: &str) -> bool {
+    matches!(name, "content_type" | "content_encoding" | "priority" | "correlation_id" | "reply_to" | "expiration" |
+                   "message_id" | "timestamp" | "type" | "user_id" | "app_id")
+  }
+
+  pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+    let value = value.trim();
+    match name {
+      "content_type" => self.content_type = Some(value.to_string()),
+      "content_encoding" => self.content_encoding = Some(value.to_string()),
+      "priority" => self.priority = Some(value.parse().map_err(|_| format!("priority must be a number from 0 to 255, got {:?}", value))?),
+      "correlation_id" => self.correlation_id = Some(value.to_string()),
+      "reply_to" => self.reply_to = Some(value.to_string()),
+      "expiration" => {
+        // The broker takes the expiration as a string but closes the channel if it isn't a number of milliseconds
+        value.parse::<u64>().map_err(|_| format!("expiration must be a number of milliseconds, got {:?}", value))?;
+        self.expiration = Some(value.to_string())
+      },
+      "message_id" => self.message_id = Some(value.to_string()),
+      "timestamp" => self.timestamp = Some(value.parse().map_err(|_| format!("timestamp must be a number of seconds since the epoch, got {:?}", value))?),
+      "type" => self.message_type = Some(value.to_string()),
+      "user_id" => self.user_id = Some(value.to_string()),
+      "app_id" => self.app_id = Some(value.to_string()),
+      _ => return Err(format!("Unknown property {:?}", name))
+    }
+    Ok(())
+  }
+
+  // The properties set here take precedence over the defaults
+  pub fn or(self, defaults: &Properties) -> Properties {
+    let defaults = defaults.clone();
+    Properties{
+      content_type: self.content_type.or(defaults.content_type),
+      content_encoding: self.content_encoding.or(defaults.content_encoding),
+      priority: self.priority.or(defaults.priority),
+      correlation_id: self.correlation_id.or(defaults.correlation_id),
+      reply_to: self.reply_to.or(defaults.reply_to),
+      expiration: self.expiration.or(defaults.expiration),
+      message_id: self.message_id.or(defaults.message_id),
+      timestamp: self.timestamp.or(defaults.timestamp),
+      message_type: self.message_type.or(defaults.message_type),
+      user_id: self.user_id.or(defaults.user_id),
+      app_id: self.app_id.or(defaults.app_id)
+    }
+  }
+
+  pub fn basic_properties(self, headers: Option<FieldTable>, delivery_mode: u8) -> BasicProperties {
+    let mut properties = BasicProperties::default()
+      .with_content_type(ShortString::from(self.content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string())))
+      .with_delivery_mode(delivery_mode);
+    if let Some(headers) = headers { properties = properties.with_headers(headers) }
+    if let Some(content_encoding) = self.content_encoding { properties = properties.with_content_encoding(content_encoding.into()) }
+    if let Some(priority) = self.priority { properties = properties.with_priority(priority) }
+    if let Some(correlation_id) = self.correlation_id { properties = properties.with_correlation_id(correlation_id.into()) }
+    if let Some(reply_to) = self.reply_to { properties = properties.with_reply_to(reply_to.into()) }
+    if let Some(expiration) = self.expiration { properties = properties.with_expiration(expiration.into()) }
+    if let Some(message_id) = self.message_id { properties = properties.with_message_id(message_id.into()) }
+    if let Some(timestamp) = self.timestamp { properties = properties.with_timestamp(timestamp) }
+    if let Some(message_type) = self.message_type { properties = properties.with_type(message_type.into()) }
+    if let Some(user_id) = self.user_id { properties = properties.with_user_id(user_id.into()) }
+    if let Some(app_id) = self.app_id { properties = properties.with_app_id(app_id.into()) }
+    properties
+  }
+}
+
+/*
+ * Takes the properties out of the pipe format headers, e.g. key|amqp_correlation_id: 42; amqp_reply_to: replies|message
+ * Only the headers naming a property with a single value are taken, the other amqp_ ones are sent as headers like
+ * before they were reserved.
+*/
+pub fn take_properties(headers: Option<FieldTable>) -> Result<(Properties, Option<FieldTable>), String> {
+  let mut properties = Properties::default();
+  let headers = match headers {
+    Some(headers) => headers,
+    None => return Ok((properties, None))
+  };
+  let mut remaining = FieldTable::default();
+  for (name, value) in &headers {
+    let property = name.as_str().strip_prefix(PROPERTY_HEADER_PREFIX).filter(|property| Properties::is_property(property));
+    match (property, value) {
+      (Some(property), AMQPValue::FieldArray(values)) if values.as_slice().len() == 1 => match values.as_slice()[0] {
+        AMQPValue::LongString(ref value) => properties.set(property, &value.to_string())?,
+        _ => return Err(format!("Header {} must be a string", name))
+      },
+      _ => remaining.insert(name.clone(), value.clone())
+    }
+  }
+  Ok((properties, if remaining.inner().is_empty() { None } else { Some(remaining) }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::parse_notification;
+
+  #[test]
+  fn take_properties_works() {
+    let (_, _, headers) = parse_notification("key|amqp_correlation_id: 42; amqp_priority: 5; amqp_type: order.created; X-My-Header: a, b|message");
+    assert_eq!(Ok((Properties{
+      correlation_id: Some("42".to_string()),
+      priority: Some(5),
+      message_type: Some("order.created".to_string()),
+      ..Properties::default()
+    }, Some(FieldTable::from(btreemap!{
+      "X-My-Header".into() => AMQPValue::FieldArray(vec![
+        AMQPValue::LongString("a".into()),
+        AMQPValue::LongString("b".into()),
+      ].into())
+    })))), take_properties(headers));
+    let (_, _, headers) = parse_notification("key|amqp_timestamp: 1500000000|message");
+    assert_eq!(Ok((Properties{ timestamp: Some(1500000000), ..Properties::default() }, None)), take_properties(headers));
+    assert_eq!(Ok((Properties::default(), None)), take_properties(None));
+  }
+
+  #[test]
+  fn take_properties_fails_on_invalid_values() {
+    let (_, _, headers) = parse_notification("key|amqp_priority: high|message");
+    assert!(take_properties(headers).is_err());
+    let (_, _, headers) = parse_notification("key|amqp_expiration: 1 minute|message");
+    assert!(take_properties(headers).is_err());
+  }
+
+  #[test]
+  fn take_properties_keeps_other_amqp_headers() {
+    let (_, _, headers) = parse_notification("key|amqp_reply_to: a, b; amqp_cluster_id: c|message");
+    assert_eq!(Ok((Properties::default(), headers.clone())), take_properties(headers));
+  }
+
+  #[test]
+  fn or_works() {
+    let defaults = Properties{ content_type: Some("application/json".to_string()), app_id: Some("billing".to_string()), ..Properties::default() };
+    assert_eq!(Properties{ content_type: Some("text/csv".to_string()), app_id: Some("billing".to_string()), ..Properties::default() },
+               Properties{ content_type: Some("text/csv".to_string()), ..Properties::default() }.or(&defaults));
+  }
+}
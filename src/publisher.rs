@@ -0,0 +1,355 @@
+use lapin::{BasicProperties, Channel};
+use lapin::message::BasicReturnMessage;
+use lapin::options::BasicPublishOptions;
+use lapin::publisher_confirm::Confirmation;
+use lapin::types::FieldTable;
+use std::default::Default;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_postgres::Client;
+
+use super::{Binding, Type, PayloadFormat, parse_notification};
+use super::amqp::{AmqpConnection, AmqpNode, check_amqp_entity_type, close_channel, get_amqp_entity_type, open_publisher_channel};
+use super::envelope::parse_envelope;
+use super::error::BridgeError;
+use super::properties::{Properties, take_properties};
+use super::routing::{Route, route};
+use super::rpc::Calls;
+
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+  Unconfirmed,
+  Acked,
+  Nacked,
+  // Reply code and text of the broker
+  Returned(u16, String),
+  // Nacked or returned, then taken by the fallback exchange or table
+  FellBack
+}
+
+impl Delivery {
+  // The message was taken by the broker or by a fallback, nothing is left to retry
+  pub fn is_handled(&self) -> bool {
+    matches!(self, Delivery::Unconfirmed | Delivery::Acked | Delivery::FellBack)
+  }
+}
+
+#[derive(Clone)]
+pub struct Message{
+  pub exchange: String,
+  pub key: String,
+  pub properties: BasicProperties,
+  pub body: String
+}
+
+// Counters of a binding, kept across the restarts of its publisher
+#[derive(Debug, Default)]
+pub struct PublisherStats{
+  published: AtomicU64,
+  undelivered: AtomicU64,
+  node: Mutex<Option<String>>
+}
+
+impl PublisherStats {
+  // Messages taken by the broker
+  pub fn published(&self) -> u64 {
+    self.published.load(Ordering::Relaxed)
+  }
+
+  // Messages that went to the failure path
+  pub fn undelivered(&self) -> u64 {
+    self.undelivered.load(Ordering::Relaxed)
+  }
+
+  // AMQP node the binding publishes to while its publisher runs
+  pub fn node(&self) -> Option<String> {
+    self.node.lock().unwrap().clone()
+  }
+
+  fn set_node(&self, node: Option<&AmqpNode>) {
+    *self.node.lock().unwrap() = node.map(|node| node.name.clone());
+  }
+}
+
+/*
+ * Publishes the messages of a binding through its own channel of the shared AMQP connection, the channel is
+ * reopened whenever the connection is lost.
+*/
+pub struct Publisher{
+  amqp: Arc<AmqpConnection>,
+  binding: Binding,
+  delivery_mode: u8,
+  node: AmqpNode,
+  channel: Channel,
+  amqp_entity_type: Type,
+  returned_count: u64,
+  // Without waiting, a lost connection is only tried once per node and the publish fails with a connection error
+  waits_for_connection: bool,
+  stats: Arc<PublisherStats>,
+  // The published RPC calls wait for their reply there
+  calls: Arc<Calls>,
+  // Used for the fallback table
+  pg_client: Arc<Client>
+}
+
+impl Publisher {
+  pub async fn new(amqp: Arc<AmqpConnection>, binding: Binding, delivery_mode: u8, pg_client: Arc<Client>, stats: Arc<PublisherStats>, calls: Arc<Calls>) -> Result<Publisher, BridgeError> {
+    let node = amqp.get().await?;
+    let (channel, amqp_entity_type) = open_binding_channel(&node, &binding).await?;
+    info!("{:?} -> {:?} publishing to AMQP server {}", binding.pg_channel, binding.amqp_entity, node.name);
+    stats.set_node(Some(&node));
+    Ok(Publisher{
+      amqp, binding, delivery_mode, node, channel, amqp_entity_type,
+      returned_count: 0, waits_for_connection: true, stats, calls, pg_client
+    })
+  }
+
+  // The given properties override the binding ones
+  pub fn message(&self, routing_key: &str, body: &str, headers: Option<FieldTable>, properties: Properties) -> Message {
+    let routing_key = match self.binding.options.routing_key {
+      Some(ref default_key) if routing_key.is_empty() => default_key.as_str(),
+      _ => routing_key
+    };
+    let (exchange, key) = match route(&self.binding.options.rules, &self.binding.pg_channel, routing_key, body, headers.as_ref()) {
+      // The exchange of a rule takes its routing key as is
+      Some(Route{ exchange: Some(exchange), routing_key }) => (exchange, routing_key),
+      route => {
+        let routing_key = route.map_or_else(|| routing_key.to_string(), |route| route.routing_key);
+        // Queues are published through the default exchange, with the queue name as the routing key
+        if self.amqp_entity_type == Type::Exchange {
+          (self.binding.amqp_entity.clone(), format!("{}{}", self.binding.options.routing_key_prefix.as_deref().unwrap_or(""), routing_key))
+        } else {
+          (String::new(), self.binding.amqp_entity.clone())
+        }
+      }
+    };
+    Message{
+      exchange,
+      key,
+      properties: properties.or(&self.binding.options.properties).basic_properties(headers, self.binding.options.delivery_mode.unwrap_or(self.delivery_mode)),
+      body: body.to_string()
+    }
+  }
+
+  // Builds the message of a notification or outbox payload according to the binding format
+  pub fn message_from_payload(&self, payload: &str) -> Result<Message, String> {
+    match self.binding.options.format {
+      PayloadFormat::Pipe => {
+        let (routing_key, body, headers) = parse_notification(payload);
+        let (properties, headers) = take_properties(headers)?;
+        Ok(self.message(routing_key, body, headers, properties))
+      },
+      PayloadFormat::Json => {
+        let envelope = parse_envelope(payload)?;
+        Ok(self.message(&envelope.routing_key, &envelope.body, envelope.headers, envelope.properties))
+      }
+    }
+  }
+
+  /*
+   * Publishes the message, republishing it when the broker nacks it and sending it to the failure path when it's
+   * still undelivered after all the retries. Only the errors that remain after reconnecting are returned.
+  */
+  pub async fn deliver(&mut self, message: &Message) -> Result<Delivery, BridgeError> {
+    self.handle_returned().await;
+    let binding = self.binding.clone();
+    let mut retries = 0;
+    let publication = loop {
+      let mut publication = publish(&self.channel, message).await;
+
+      // When the AMQP connection is lost retry it
+      if let Err(ref e) = publication {
+        if e.is_connection_error() {
+          error!("{:?}", e);
+          // Republish message
+          publication = match self.reconnect().await {
+            Ok(()) => publish(&self.channel, message).await,
+            Err(e) => Err(e)
+          };
+        }
+      }
+
+      match publication {
+        Ok(Delivery::Nacked) if retries < binding.options.retries => {
+          retries += 1;
+          warn!("{:?} -> {:?} {:?} message nacked by the broker, republishing (retry {} of {})",
+                binding.pg_channel, self.amqp_entity_type, binding.amqp_entity, retries, binding.options.retries);
+        },
+        _ => break publication
+      }
+    };
+
+    let fell_back = match publication{
+      Ok(Delivery::Unconfirmed) | Ok(Delivery::Acked) => {
+        self.stats.published.fetch_add(1, Ordering::Relaxed);
+        self.calls.sent(&message.properties);
+        info!("{:?} -> {:?} {:?} ( routing_key: {:?}, message: {:?} )",
+              binding.pg_channel, self.amqp_entity_type, binding.amqp_entity, message.key, message.body);
+        false
+      },
+      Ok(Delivery::Nacked) => {
+        error!("{:?} -> {:?} {:?} message nacked by the broker after {} retries",
+               binding.pg_channel, self.amqp_entity_type, binding.amqp_entity, retries);
+        self.send_to_fallback(message, "nack").await
+      },
+      Ok(Delivery::Returned(reply_code, ref reply_text)) => self.returned(message, reply_code, reply_text).await,
+      Ok(Delivery::FellBack) | Err(_) => false
+    };
+    if fell_back { Ok(Delivery::FellBack) } else { publication }
+  }
+
+  /*
+   * Without confirm mode the broker returns the unroutable messages after they were published, they're collected
+   * by the channel and take the failure path the next time the binding publishes or is idle.
+  */
+  pub async fn handle_returned(&mut self) {
+    if self.binding.options.confirm {
+      return;
+    }
+    let returned = match self.channel.wait_for_confirms().await {
+      Ok(returned) => returned,
+      Err(e) => {
+        error!("{:?} -> {:?} could not get the returned messages: {:?}", self.binding.pg_channel, self.binding.amqp_entity, e);
+        return;
+      }
+    };
+    for BasicReturnMessage{ delivery, reply_code, reply_text } in returned {
+      let message = Message{
+        exchange: delivery.exchange.to_string(),
+        key: delivery.routing_key.to_string(),
+        properties: delivery.properties,
+        body: String::from_utf8_lossy(&delivery.data).into_owned()
+      };
+      // A fallback message that can't be routed either isn't sent back to the fallback
+      if self.binding.options.fallback.as_ref() == Some(&message.exchange) {
+        error!("{:?} -> {:?} undelivered message, fallback exchange {:?} returned it with {} {} ( routing_key: {:?}, message: {:?} )",
+               self.binding.pg_channel, self.binding.amqp_entity, message.exchange, reply_code, reply_text, message.key, message.body);
+        continue;
+      }
+      self.returned(&message, reply_code, reply_text.as_str()).await;
+    }
+  }
+
+  async fn returned(&mut self, message: &Message, reply_code: u16, reply_text: &str) -> bool {
+    self.returned_count += 1;
+    warn!("{:?} -> {:?} {:?} message returned by the broker with {} {}, {} returned so far ( routing_key: {:?}, message: {:?} )",
+          self.binding.pg_channel, self.amqp_entity_type, self.binding.amqp_entity, reply_code, reply_text,
+          self.returned_count, message.key, message.body);
+    self.send_to_fallback(message, reply_text).await
+  }
+
+  /*
+   * Failure path for the messages the broker refused to take or couldn't route. They're sent to the fallback exchange
+   * and inserted in the fallback table when the binding has them, otherwise they're logged so they can still be recovered.
+   * Returns whether the exchange or the table took the message.
+  */
+  pub async fn send_to_fallback(&mut self, message: &Message, reason: &str) -> bool {
+    self.stats.undelivered.fetch_add(1, Ordering::Relaxed);
+    let binding = &self.binding;
+    let mut fell_back = false;
+    if binding.options.fallback.is_none() && binding.options.fallback_table.is_none() {
+      error!("{:?} -> {:?} undelivered message ( reason: {:?}, routing_key: {:?}, message: {:?} )",
+             binding.pg_channel, binding.amqp_entity, reason, message.key, message.body);
+    }
+    if let Some(ref fallback) = binding.options.fallback {
+      let fallback_message = Message{ exchange: fallback.clone(), ..message.clone() };
+      match publish(&self.channel, &fallback_message).await {
+        Ok(Delivery::Unconfirmed) | Ok(Delivery::Acked) => {
+          fell_back = true;
+          warn!("{:?} -> {:?} message sent to fallback exchange {:?} ( reason: {:?}, routing_key: {:?}, message: {:?} )",
+                binding.pg_channel, binding.amqp_entity, fallback, reason, message.key, message.body);
+        },
+        failure => {
+          error!("{:?} -> {:?} undelivered message, fallback exchange {:?} failed with {:?} ( reason: {:?}, routing_key: {:?}, message: {:?} )",
+                 binding.pg_channel, binding.amqp_entity, fallback, failure, reason, message.key, message.body);
+        }
+      }
+    }
+    if let Some(ref fallback_table) = binding.options.fallback_table {
+      let insert_command = format!("INSERT INTO {}(pg_channel, amqp_entity, routing_key, reason, message) VALUES ($1, $2, $3, $4, $5)", fallback_table);
+      let inserted = self.pg_client.execute(insert_command.as_str(),
+                                            &[&binding.pg_channel, &binding.amqp_entity, &message.key, &reason, &message.body]).await;
+      match inserted {
+        Ok(_) => {
+          fell_back = true;
+          warn!("{:?} -> {:?} message inserted in fallback table {:?} ( reason: {:?}, routing_key: {:?}, message: {:?} )",
+                binding.pg_channel, binding.amqp_entity, fallback_table, reason, message.key, message.body);
+        },
+        Err(e) => {
+          error!("{:?} -> {:?} undelivered message, fallback table {:?} failed with {:?} ( reason: {:?}, routing_key: {:?}, message: {:?} )",
+                 binding.pg_channel, binding.amqp_entity, fallback_table, e, reason, message.key, message.body);
+        }
+      }
+    }
+    fell_back
+  }
+
+  // The payloads that can't be parsed take the failure path as they are
+  pub async fn send_invalid_payload_to_fallback(&mut self, payload: &str, error: &str){
+    error!("{:?} -> {:?} invalid payload: {}", self.binding.pg_channel, self.binding.amqp_entity, error);
+    let message = self.message("", payload, None, Properties::default());
+    self.send_to_fallback(&message, error).await;
+  }
+
+  pub async fn close(mut self){
+    self.handle_returned().await;
+    self.stats.set_node(None);
+    close_channel(&self.channel).await;
+    println!("Closed the AMQP channel for {} channel", self.binding.pg_channel);
+  }
+
+  pub fn set_waits_for_connection(&mut self, waits_for_connection: bool) {
+    self.waits_for_connection = waits_for_connection;
+  }
+
+  // As far as the client knows, a connection lost without an error is only noticed by the heartbeats
+  pub fn is_connected(&self) -> bool {
+    self.node.connection.status().connected() && self.channel.status().connected()
+  }
+
+  // Fails over to another node when the connection of the channel is the one lost
+  pub async fn reconnect(&mut self) -> Result<(), BridgeError>{
+    let node = self.amqp.replace(&self.node, self.waits_for_connection).await?;
+    let (channel, amqp_entity_type) = open_binding_channel(&node, &self.binding).await?;
+    if node.name != self.node.name {
+      warn!("{:?} -> {:?} failed over from AMQP server {} to {}", self.binding.pg_channel, self.binding.amqp_entity, self.node.name, node.name);
+    }
+    self.stats.set_node(Some(&node));
+    self.node = node;
+    self.channel = channel;
+    self.amqp_entity_type = amqp_entity_type;
+    Ok(())
+  }
+}
+
+async fn open_binding_channel(node: &AmqpNode, binding: &Binding) -> Result<(Channel, Type), BridgeError> {
+  let amqp_entity_type = match binding.options.entity_type {
+    Some(ref entity_type) => {
+      check_amqp_entity_type(&node.connection, &binding.amqp_entity, entity_type, binding.options.exchange_kind).await?;
+      entity_type.clone()
+    },
+    None => get_amqp_entity_type(&node.connection, &binding.amqp_entity).await?
+  };
+  // The rules can route to other exchanges, "" being the default one
+  for exchange in binding.options.rules.iter().filter_map(|rule| rule.exchange.as_ref()).filter(|exchange| !exchange.is_empty()) {
+    check_amqp_entity_type(&node.connection, exchange, &Type::Exchange, None).await?;
+  }
+  let channel = open_publisher_channel(&node.connection, binding.options.confirm).await?;
+  Ok((channel, amqp_entity_type))
+}
+
+/*
+ * Messages are published as mandatory so the unroutable ones are returned by the broker, in confirm mode before the
+ * ack. Messages are published one at a time, so the confirm is always the one of the last message.
+*/
+async fn publish(channel: &Channel, message: &Message) -> Result<Delivery, BridgeError> {
+  let publisher_confirm = channel.basic_publish(
+    message.exchange.as_str(), message.key.as_str(), BasicPublishOptions{ mandatory: true, ..BasicPublishOptions::default() },
+    message.body.as_bytes(), message.properties.clone()).await?;
+  Ok(match publisher_confirm.await? {
+    Confirmation::NotRequested => Delivery::Unconfirmed,
+    Confirmation::Ack(None) => Delivery::Acked,
+    Confirmation::Ack(Some(returned)) => Delivery::Returned(returned.reply_code, returned.reply_text.to_string()),
+    Confirmation::Nack(_) => Delivery::Nacked
+  })
+}
@@ -0,0 +1,62 @@
+use tokio_postgres::Client;
+
+pub const REFERENCE_PREFIX: &str = "@ref:";
+const REFERENCE_SEPARATOR: char = ':';
+
+/*
+ * A notification that points to its payload, stored in the payload table of the binding because it's over the
+ * 8000 bytes NOTIFY limit, e.g. @ref:rabbitmq.payloads:1234
+*/
+#[derive(Debug, PartialEq)]
+pub struct Reference<'a>{
+  pub table: &'a str,
+  pub id: i64
+}
+
+// None when the notification carries its payload
+pub fn parse_reference(payload: &str) -> Option<Result<Reference<'_>, String>> {
+  let reference = payload.trim();
+  let table_and_id: Vec<&str> = reference.strip_prefix(REFERENCE_PREFIX)?.rsplitn(2, REFERENCE_SEPARATOR).collect();
+  Some(match table_and_id[..] {
+    [id, table] if !table.trim().is_empty() => id.trim().parse()
+      .map(|id| Reference{ table: table.trim(), id })
+      .map_err(|_| format!("The id of the reference {:?} must be a number", reference)),
+    _ => Err(format!("A reference must be like {}table:id, got {:?}", REFERENCE_PREFIX, reference))
+  })
+}
+
+impl<'a> Reference<'a> {
+  // Only the payload table of the binding can be read, the table name goes into the SQL statement
+  pub async fn fetch(&self, pg_client: &Client, payload_table: &str) -> Result<String, String> {
+    if self.table != payload_table {
+      return Err(format!("The reference to {:?} isn't to the payload table {:?}", self.table, payload_table));
+    }
+    let rows = pg_client.query(format!("SELECT payload FROM {} WHERE id = $1", self.table).as_str(), &[&self.id]).await
+      .map_err(|e| format!("Could not fetch the payload {} from {:?}: {}", self.id, self.table, e))?;
+    match rows.first() {
+      Some(row) => row.try_get(0).map_err(|e| format!("Invalid payload {} in {:?}: {}", self.id, self.table, e)),
+      None => Err(format!("The payload {} isn't in {:?}", self.id, self.table))
+    }
+  }
+
+  pub async fn delete(&self, pg_client: &Client) {
+    if let Err(e) = pg_client.execute(format!("DELETE FROM {} WHERE id = $1", self.table).as_str(), &[&self.id]).await {
+      error!("Could not delete the payload {} from {:?}: {:?}", self.id, self.table, e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_reference_works() {
+    assert_eq!(None, parse_reference("my_key|A message"));
+    assert_eq!(Some(Ok(Reference{ table: "rabbitmq.payloads", id: 1234 })), parse_reference(" @ref:rabbitmq.payloads:1234 "));
+    assert_eq!(Some(Ok(Reference{ table: "payloads", id: 1 })), parse_reference("@ref: payloads : 1"));
+    assert!(parse_reference("@ref:payloads").unwrap().is_err());
+    assert!(parse_reference("@ref::1").unwrap().is_err());
+    assert!(parse_reference("@ref:payloads:abc").unwrap().is_err());
+  }
+}
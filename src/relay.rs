@@ -0,0 +1,160 @@
+use lapin::{Channel, Connection};
+use lapin::options::{QueueBindOptions, QueueDeclareOptions};
+use lapin::types::FieldTable;
+
+use super::{EXCHANGE_PREFIX, OPTIONS_SEPARATOR, OPTION_NAME_VALUE_SEPARATOR, OPTION_SEPARATOR, QUEUE_PREFIX, SEPARATOR, Type};
+use super::amqp::{check_amqp_entity_type, get_amqp_entity_type};
+use super::error::BridgeError;
+
+const RELAY_SEPARATOR: char = ':';
+const DEFAULT_BINDING_KEY: &str = "#";
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct RelayOptions{
+  // Queue or Exchange, found on the broker when not given
+  pub entity_type: Option<Type>,
+  // Binding key of the queue the bridge declares for an exchange
+  pub binding_key: String,
+  pub prefetch: u16
+}
+
+impl Default for RelayOptions {
+  fn default() -> RelayOptions {
+    RelayOptions{ entity_type: None, binding_key: DEFAULT_BINDING_KEY.to_string(), prefetch: 10 }
+  }
+}
+
+/*
+ * Sends the messages of an AMQP queue or exchange to a PostgreSQL channel as `pg_notify(pg_channel, 'routing_key|body')`,
+ * the format of the bindings. An exchange is consumed through an exclusive, auto-delete queue, so its messages are only
+ * relayed while the bridge is connected.
+*/
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Relay{
+  pub amqp_entity: String,
+  pub pg_channel: String,
+  pub options: RelayOptions
+}
+
+impl Relay {
+  pub fn new(amqp_entity: &str, pg_channel: &str) -> Relay {
+    Relay{ amqp_entity: amqp_entity.to_string(), pg_channel: pg_channel.to_string(), options: RelayOptions::default() }
+  }
+
+  pub(crate) fn finish(self) -> Result<Relay, BridgeError> {
+    if self.amqp_entity.trim().is_empty() || self.pg_channel.trim().is_empty() {
+      return Err(BridgeError::Config(format!("Relays need an amqp entity and a PostgreSQL channel, got {:?} and {:?}", self.amqp_entity, self.pg_channel)));
+    }
+    if self.options.prefetch == 0 {
+      return Err(BridgeError::Config(format!("The prefetch of the {:?} relay must be at least 1", self.amqp_entity)));
+    }
+    if self.options.entity_type == Some(Type::Queue) && self.options.binding_key != DEFAULT_BINDING_KEY {
+      return Err(BridgeError::Config(format!("The relay of the queue {:?} cannot have a binding key", self.amqp_entity)));
+    }
+    Ok(self)
+  }
+
+  // The queue the messages are consumed from, declared and bound on every connection when the entity is an exchange
+  pub(crate) async fn declare_queue(&self, connection: &Connection, channel: &Channel) -> Result<String, BridgeError> {
+    let entity_type = match self.options.entity_type {
+      Some(ref entity_type) => {
+        check_amqp_entity_type(connection, &self.amqp_entity, entity_type, None).await?;
+        entity_type.clone()
+      },
+      None => get_amqp_entity_type(connection, &self.amqp_entity).await?
+    };
+    match entity_type {
+      Type::Queue => Ok(self.amqp_entity.clone()),
+      Type::Exchange => {
+        let options = QueueDeclareOptions{ exclusive: true, auto_delete: true, ..QueueDeclareOptions::default() };
+        let queue = channel.queue_declare("", options, FieldTable::default()).await?;
+        channel.queue_bind(queue.name().as_str(), &self.amqp_entity, &self.options.binding_key,
+                           QueueBindOptions::default(), FieldTable::default()).await?;
+        Ok(queue.name().to_string())
+      }
+    }
+  }
+}
+
+// A body with the separator gets empty headers so parse_notification doesn't take its first part as headers
+pub(crate) fn notification_payload(routing_key: &str, body: &str) -> String {
+  if body.contains(SEPARATOR) {
+    format!("{}{}{}{}", routing_key, SEPARATOR, SEPARATOR, body)
+  } else {
+    format!("{}{}{}", routing_key, SEPARATOR, body)
+  }
+}
+
+// e.g. BRIDGE_RELAYS="exchange:events:app_events?key=orders.#,queue:alerts:alerts"
+pub fn parse_bridge_relays(bridge_relays: &str) -> Result<Vec<Relay>, BridgeError> {
+  let mut relays = Vec::new();
+  for relay in bridge_relays.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    let (entity_type, relay) =
+      if let Some(relay) = relay.strip_prefix(EXCHANGE_PREFIX) {
+        (Some(Type::Exchange), relay)
+      } else if let Some(relay) = relay.strip_prefix(QUEUE_PREFIX) {
+        (Some(Type::Queue), relay)
+      } else {
+        (None, relay)
+      };
+    let relay_and_options: Vec<&str> = relay.splitn(2, OPTIONS_SEPARATOR).collect();
+    let entity_and_channel: Vec<&str> = relay_and_options[0].splitn(2, RELAY_SEPARATOR).map(|x| x.trim()).collect();
+    let mut options = parse_relay_options(relay_and_options.get(1).unwrap_or(&""))?;
+    options.entity_type = entity_type;
+    relays.push(Relay{
+      amqp_entity: entity_and_channel[0].to_string(),
+      pg_channel: entity_and_channel.get(1).unwrap_or(&"").to_string(),
+      options
+    }.finish()?);
+  }
+  check_relays(relays)
+}
+
+pub(crate) fn check_relays(mut relays: Vec<Relay>) -> Result<Vec<Relay>, BridgeError> {
+  relays.sort();
+  if relays.windows(2).any(|pair| pair[0].amqp_entity == pair[1].amqp_entity && pair[0].pg_channel == pair[1].pg_channel) {
+    return Err(BridgeError::Config("Cannot relay the same amqp entity to the same PostgreSQL channel twice".to_string()));
+  }
+  Ok(relays)
+}
+
+fn parse_relay_options(options: &str) -> Result<RelayOptions, BridgeError> {
+  let mut relay_options = RelayOptions::default();
+  for option in options.split(OPTION_SEPARATOR).map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    let name_value: Vec<&str> = option.splitn(2, OPTION_NAME_VALUE_SEPARATOR).map(|x| x.trim()).collect();
+    match name_value[..] {
+      ["key", key] if !key.is_empty() => relay_options.binding_key = key.to_string(),
+      ["prefetch", prefetch] =>
+        relay_options.prefetch = prefetch.parse().map_err(|_| BridgeError::Config(format!("Relay option prefetch must be a number, got \"{}\"", prefetch)))?,
+      _ => return Err(BridgeError::Config(format!("Unknown relay option \"{}\"", option)))
+    }
+  }
+  Ok(relay_options)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::parse_notification;
+
+  #[test]
+  fn parse_bridge_relays_works() {
+    assert_eq!(vec![
+      Relay::new("alerts", "alerts"),
+      Relay{ amqp_entity: "events".to_string(), pg_channel: "app_events".to_string(),
+             options: RelayOptions{ entity_type: Some(Type::Exchange), binding_key: "orders.#".to_string(), prefetch: 1 } }
+    ], parse_bridge_relays(" exchange:events : app_events ? key=orders.# & prefetch=1, alerts:alerts,").unwrap());
+    assert!(parse_bridge_relays("alerts").is_err());
+    assert!(parse_bridge_relays("queue:alerts:alerts?key=high").is_err());
+    assert!(parse_bridge_relays("alerts:alerts?prefetch=0").is_err());
+    assert!(parse_bridge_relays("alerts:alerts,queue:alerts:alerts").is_err());
+  }
+
+  #[test]
+  fn notification_payload_works() {
+    assert_eq!(("orders.created", "A message", None), parse_notification(&notification_payload("orders.created", "A message")));
+    let payload = notification_payload("orders.created", "A | message");
+    let (routing_key, body, _) = parse_notification(&payload);
+    assert_eq!(("orders.created", "A | message"), (routing_key, body));
+  }
+}
@@ -0,0 +1,199 @@
+use lapin::types::{AMQPValue, FieldTable};
+use serde_json::Value;
+
+const POINTER_PREFIX: char = '/';
+const HEADER_PREFIX: &str = "header:";
+const PG_CHANNEL: &str = "pg_channel";
+
+const VALUE_PLACEHOLDER: &str = "{value}";
+const PG_CHANNEL_PLACEHOLDER: &str = "{pg_channel}";
+const ROUTING_KEY_PLACEHOLDER: &str = "{routing_key}";
+
+// Where a rule takes the value it matches from
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum Field {
+  // JSON pointer into the message body, e.g. /tenant
+  Pointer(String),
+  Header(String),
+  PgChannel
+}
+
+impl Field {
+  // /a/json/pointer, header:Name or pg_channel
+  pub fn parse(field: &str) -> Result<Field, String> {
+    let field = field.trim();
+    if field.starts_with(POINTER_PREFIX) {
+      Ok(Field::Pointer(field.to_string()))
+    } else if let Some(name) = field.strip_prefix(HEADER_PREFIX).filter(|name| !name.is_empty()) {
+      Ok(Field::Header(name.trim().to_string()))
+    } else if field == PG_CHANNEL {
+      Ok(Field::PgChannel)
+    } else {
+      Err(format!("A rule can only match a json pointer(e.g. /tenant), header:<name> or pg_channel, got {:?}", field))
+    }
+  }
+
+  fn value(&self, pg_channel: &str, json: Option<&Value>, headers: Option<&FieldTable>) -> Option<String> {
+    match self {
+      Field::Pointer(pointer) => json.and_then(|json| json.pointer(pointer)).and_then(json_value),
+      Field::Header(name) => headers.and_then(|headers| headers.inner().get(name.as_str())).and_then(header_value),
+      Field::PgChannel => Some(pg_channel.to_string())
+    }
+  }
+}
+
+/*
+ * Picks the exchange and routing key of a message, e.g. with `when = "/tenant"` and
+ * `routing_key = "tenant.{value}.events"` a {"tenant": "acme"} body goes to tenant.acme.events.
+ * A rule without `when` matches every message, so it's the default route when it's the last one.
+*/
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Default)]
+pub struct Rule{
+  pub when: Option<Field>,
+  // Only matches when the value is this one, otherwise any value does
+  pub equals: Option<String>,
+  // Overrides the amqp entity of the binding
+  pub exchange: Option<String>,
+  // Template with the {value}, {pg_channel} and {routing_key} placeholders
+  pub routing_key: Option<String>
+}
+
+impl Rule {
+  pub fn check(&self) -> Result<(), String> {
+    if self.exchange.is_none() && self.routing_key.is_none() {
+      return Err("A routing rule must set an exchange or a routing_key".to_string());
+    }
+    if self.when.is_none() && self.equals.is_some() {
+      return Err("A routing rule with equals needs a when".to_string());
+    }
+    if let Some(ref template) = self.routing_key {
+      if self.when.is_none() && template.contains(VALUE_PLACEHOLDER) {
+        return Err(format!("The routing_key {:?} has a {} but its rule has no when", template, VALUE_PLACEHOLDER));
+      }
+      let rest = template.replace(VALUE_PLACEHOLDER, "").replace(PG_CHANNEL_PLACEHOLDER, "").replace(ROUTING_KEY_PLACEHOLDER, "");
+      if rest.contains('{') || rest.contains('}') {
+        return Err(format!("The routing_key {:?} can only have the {}, {} and {} placeholders", template, VALUE_PLACEHOLDER, PG_CHANNEL_PLACEHOLDER, ROUTING_KEY_PLACEHOLDER));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Route{
+  // None keeps the amqp entity of the binding
+  pub exchange: Option<String>,
+  pub routing_key: String
+}
+
+// The first rule that matches gives the route, the binding routes the message as usual when none does
+pub fn route(rules: &[Rule], pg_channel: &str, routing_key: &str, body: &str, headers: Option<&FieldTable>) -> Option<Route> {
+  // The body is only parsed once, and only when a rule looks into it
+  let json: Option<Value> =
+    if rules.iter().any(|rule| matches!(rule.when, Some(Field::Pointer(_)))) {
+      serde_json::from_str(body).ok()
+    } else {
+      None
+    };
+  for rule in rules {
+    let value = match rule.when {
+      Some(ref field) => match field.value(pg_channel, json.as_ref(), headers) {
+        Some(value) => Some(value),
+        None => continue
+      },
+      None => None
+    };
+    if rule.equals.is_some() && rule.equals != value {
+      continue;
+    }
+    let routing_key = match rule.routing_key {
+      Some(ref template) => template
+        .replace(VALUE_PLACEHOLDER, value.as_deref().unwrap_or(""))
+        .replace(PG_CHANNEL_PLACEHOLDER, pg_channel)
+        .replace(ROUTING_KEY_PLACEHOLDER, routing_key),
+      None => routing_key.to_string()
+    };
+    return Some(Route{ exchange: rule.exchange.clone(), routing_key });
+  }
+  None
+}
+
+// Objects, arrays and nulls don't make a routing key
+fn json_value(value: &Value) -> Option<String> {
+  match value {
+    Value::String(value) => Some(value.clone()),
+    Value::Number(value) => Some(value.to_string()),
+    Value::Bool(value) => Some(value.to_string()),
+    _ => None
+  }
+}
+
+// The pipe format headers are arrays, their first value is taken
+fn header_value(value: &AMQPValue) -> Option<String> {
+  match value {
+    AMQPValue::LongString(value) => Some(value.to_string()),
+    AMQPValue::ShortString(value) => Some(value.to_string()),
+    AMQPValue::Boolean(value) => Some(value.to_string()),
+    AMQPValue::LongLongInt(value) => Some(value.to_string()),
+    AMQPValue::LongInt(value) => Some(value.to_string()),
+    AMQPValue::Double(value) => Some(value.to_string()),
+    AMQPValue::FieldArray(values) => values.as_slice().first().and_then(header_value),
+    _ => None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::parse_notification;
+
+  fn rule(when: Option<&str>, equals: Option<&str>, exchange: Option<&str>, routing_key: Option<&str>) -> Rule {
+    Rule{
+      when: when.map(|when| Field::parse(when).unwrap()),
+      equals: equals.map(str::to_string),
+      exchange: exchange.map(str::to_string),
+      routing_key: routing_key.map(str::to_string)
+    }
+  }
+
+  fn route_to(exchange: Option<&str>, routing_key: &str) -> Option<Route> {
+    Some(Route{ exchange: exchange.map(str::to_string), routing_key: routing_key.to_string() })
+  }
+
+  #[test]
+  fn route_works() {
+    let rules = vec![
+      rule(Some("/priority"), Some("high"), Some("urgent_events"), None),
+      rule(Some("/tenant"), None, None, Some("tenant.{value}.events")),
+      rule(Some("header:X-Region"), None, Some("regional_events"), Some("{value}.{routing_key}")),
+      rule(None, None, Some("other_events"), Some("{pg_channel}.{routing_key}"))
+    ];
+    assert_eq!(route_to(None, "tenant.acme.events"), route(&rules, "events", "", r#"{"tenant": "acme"}"#, None));
+    assert_eq!(route_to(Some("urgent_events"), "created"), route(&rules, "events", "created", r#"{"tenant": "acme", "priority": "high"}"#, None));
+    assert_eq!(route_to(None, "tenant.42.events"), route(&rules, "events", "", r#"{"tenant": 42, "priority": "low"}"#, None));
+
+    let (routing_key, body, headers) = parse_notification("created|X-Region: eu, us|Not json");
+    assert_eq!(route_to(Some("regional_events"), "eu.created"), route(&rules, "events", routing_key, body, headers.as_ref()));
+    assert_eq!(route_to(Some("other_events"), "events.created"), route(&rules, "events", "created", r#"{"tenant": null}"#, None));
+    assert_eq!(None, route(&rules[..3], "events", "created", "Not json", None));
+  }
+
+  #[test]
+  fn route_by_pg_channel_works() {
+    let rules = vec![rule(Some("pg_channel"), Some("audit"), None, Some("audit.{routing_key}"))];
+    assert_eq!(route_to(None, "audit.login"), route(&rules, "audit", "login", "", None));
+    assert_eq!(None, route(&rules, "events", "login", "", None));
+  }
+
+  #[test]
+  fn rule_check_fails_on_invalid_rules() {
+    assert!(Field::parse("tenant").is_err());
+    assert!(Field::parse("header:").is_err());
+    assert!(rule(Some("/tenant"), None, None, Some("tenant.{value}")).check().is_ok());
+    assert!(rule(None, None, Some("events"), None).check().is_ok());
+    assert!(rule(Some("/tenant"), None, None, None).check().is_err());
+    assert!(rule(None, Some("acme"), None, Some("acme")).check().is_err());
+    assert!(rule(None, None, None, Some("tenant.{value}")).check().is_err());
+    assert!(rule(Some("/tenant"), None, None, Some("tenant.{tenant}")).check().is_err());
+  }
+}
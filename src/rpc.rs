@@ -0,0 +1,218 @@
+use lapin::BasicProperties;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_postgres::{Client, Statement};
+
+use super::{OPTIONS_SEPARATOR, OPTION_NAME_VALUE_SEPARATOR, OPTION_SEPARATOR};
+use super::consumer::is_qualified_name;
+use super::error::BridgeError;
+
+const RPC_SEPARATOR: char = ':';
+
+pub const REPLIED: &str = "replied";
+pub const TIMED_OUT: &str = "timed_out";
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct RpcOptions{
+  // PostgreSQL channel notified with the correlation id of every reply or timeout
+  pub notify: Option<String>,
+  // Time a call waits for its reply once published
+  pub timeout: Duration,
+  pub prefetch: u16
+}
+
+impl Default for RpcOptions {
+  fn default() -> RpcOptions {
+    RpcOptions{ notify: None, timeout: Duration::from_secs(30), prefetch: 10 }
+  }
+}
+
+/*
+ * Writes the replies of the calls made from SQL into a table keyed by correlation id. A call is a message published
+ * through a binding with the reply queue as its reply_to and a correlation_id, e.g.
+ * pg_notify('rpc', 'compute|amqp_reply_to: replies; amqp_correlation_id: 42|{"n": 10}'). The table needs the
+ * correlation_id(primary key), status, routing_key, headers(jsonb) and body columns.
+*/
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Rpc{
+  pub reply_queue: String,
+  pub table: String,
+  pub options: RpcOptions
+}
+
+impl Rpc {
+  pub fn new(reply_queue: &str, table: &str) -> Rpc {
+    Rpc{ reply_queue: reply_queue.to_string(), table: table.to_string(), options: RpcOptions::default() }
+  }
+
+  // The table name goes into the SQL statement like the function of a consumer
+  pub(crate) fn finish(self) -> Result<Rpc, BridgeError> {
+    if self.reply_queue.trim().is_empty() {
+      return Err(BridgeError::Config(format!("RPCs need a reply queue, got {:?}", self.reply_queue)));
+    }
+    if !is_qualified_name(&self.table) {
+      return Err(BridgeError::Config(format!("The table of an RPC must be a name like rabbitmq.rpc_replies, got {:?}", self.table)));
+    }
+    if self.options.notify.as_ref().is_some_and(|notify| notify.trim().is_empty()) {
+      return Err(BridgeError::Config(format!("The notify channel of the {:?} RPC cannot be empty", self.reply_queue)));
+    }
+    if self.options.timeout == Duration::from_secs(0) || self.options.prefetch == 0 {
+      return Err(BridgeError::Config(format!("The timeout and prefetch of the {:?} RPC must be at least 1", self.reply_queue)));
+    }
+    Ok(self)
+  }
+
+  // A reply, or a timeout, is only written once, the notification is only sent when it is
+  pub(crate) fn statement(&self) -> String {
+    let insert = format!("INSERT INTO {}(correlation_id, status, routing_key, headers, body) VALUES ($1, $2, $3, $4::text::jsonb, $5) \
+                          ON CONFLICT (correlation_id) DO NOTHING", self.table);
+    match self.options.notify {
+      Some(_) => format!("WITH reply AS ({} RETURNING correlation_id) SELECT pg_notify($6, correlation_id) FROM reply", insert),
+      None => insert
+    }
+  }
+
+  pub(crate) async fn record(&self, pg_client: &Client, statement: &Statement, reply: &Reply<'_>) -> Result<u64, tokio_postgres::Error> {
+    let Reply{ correlation_id, status, routing_key, ref headers, body } = *reply;
+    match self.options.notify {
+      Some(ref notify) => pg_client.execute(statement, &[&correlation_id, &status, &routing_key, headers, &body, notify]).await,
+      None => pg_client.execute(statement, &[&correlation_id, &status, &routing_key, headers, &body]).await
+    }
+  }
+}
+
+// A row of the RPC table, a timed out call only has its correlation id and status
+pub(crate) struct Reply<'a>{
+  pub correlation_id: Option<&'a str>,
+  pub status: &'a str,
+  pub routing_key: Option<&'a str>,
+  // Json object
+  pub headers: Option<String>,
+  pub body: Option<&'a str>
+}
+
+impl<'a> Reply<'a> {
+  pub fn timed_out(correlation_id: &'a str) -> Reply<'a> {
+    Reply{ correlation_id: Some(correlation_id), status: TIMED_OUT, routing_key: None, headers: None, body: None }
+  }
+}
+
+/*
+ * The calls waiting for their reply, by reply queue and correlation id. They're kept in memory, so the calls pending
+ * when the bridge restarts don't time out.
+*/
+#[derive(Debug, Default)]
+pub struct Calls{
+  timeouts: HashMap<String, Duration>,
+  pending: Mutex<HashMap<(String, String), Instant>>
+}
+
+impl Calls {
+  pub(crate) fn new(rpcs: &[Rpc]) -> Calls {
+    Calls{
+      timeouts: rpcs.iter().map(|rpc| (rpc.reply_queue.clone(), rpc.options.timeout)).collect(),
+      pending: Mutex::new(HashMap::new())
+    }
+  }
+
+  // Only the messages that reply to an RPC queue are calls
+  pub(crate) fn sent(&self, properties: &BasicProperties) {
+    if let (Some(reply_to), Some(correlation_id)) = (properties.reply_to(), properties.correlation_id()) {
+      if let Some(timeout) = self.timeouts.get(reply_to.as_str()) {
+        self.pending.lock().unwrap().insert((reply_to.to_string(), correlation_id.to_string()), Instant::now() + *timeout);
+      }
+    }
+  }
+
+  pub(crate) fn replied(&self, reply_queue: &str, correlation_id: &str) {
+    self.pending.lock().unwrap().remove(&(reply_queue.to_string(), correlation_id.to_string()));
+  }
+
+  // Takes the correlation ids of the calls past their timeout
+  pub(crate) fn expired(&self, reply_queue: &str) -> Vec<String> {
+    let now = Instant::now();
+    let mut pending = self.pending.lock().unwrap();
+    let expired: Vec<(String, String)> = pending.iter()
+      .filter(|((queue, _), deadline)| queue == reply_queue && **deadline <= now)
+      .map(|(call, _)| call.clone()).collect();
+    expired.into_iter().map(|call| {
+      pending.remove(&call);
+      call.1
+    }).collect()
+  }
+}
+
+// e.g. BRIDGE_RPC="replies:rabbitmq.rpc_replies?notify=rpc_replies&timeout=10"
+pub fn parse_bridge_rpcs(bridge_rpcs: &str) -> Result<Vec<Rpc>, BridgeError> {
+  let mut rpcs = Vec::new();
+  for rpc in bridge_rpcs.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    let queue_and_rest: Vec<&str> = rpc.splitn(2, RPC_SEPARATOR).collect();
+    let table_and_options: Vec<&str> = queue_and_rest.get(1).unwrap_or(&"").splitn(2, OPTIONS_SEPARATOR).collect();
+    rpcs.push(Rpc{
+      reply_queue: queue_and_rest[0].trim().to_string(),
+      table: table_and_options[0].trim().to_string(),
+      options: parse_rpc_options(table_and_options.get(1).unwrap_or(&""))?
+    }.finish()?);
+  }
+  check_rpcs(rpcs)
+}
+
+pub(crate) fn check_rpcs(mut rpcs: Vec<Rpc>) -> Result<Vec<Rpc>, BridgeError> {
+  rpcs.sort();
+  if rpcs.windows(2).any(|pair| pair[0].reply_queue == pair[1].reply_queue) {
+    return Err(BridgeError::Config("Cannot have several RPCs with the same reply queue".to_string()));
+  }
+  Ok(rpcs)
+}
+
+fn parse_rpc_options(options: &str) -> Result<RpcOptions, BridgeError> {
+  let mut rpc_options = RpcOptions::default();
+  let number = |name: &str, value: &str| value.parse::<u64>().map_err(|_| BridgeError::Config(format!("RPC option {} must be a number, got \"{}\"", name, value)));
+  for option in options.split(OPTION_SEPARATOR).map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    let name_value: Vec<&str> = option.splitn(2, OPTION_NAME_VALUE_SEPARATOR).map(|x| x.trim()).collect();
+    match name_value[..] {
+      ["notify", notify] => rpc_options.notify = Some(notify.to_string()),
+      ["timeout", timeout] => rpc_options.timeout = Duration::from_secs(number("timeout", timeout)?),
+      ["prefetch", prefetch] =>
+        rpc_options.prefetch = prefetch.parse().map_err(|_| BridgeError::Config(format!("RPC option prefetch must be a number, got \"{}\"", prefetch)))?,
+      _ => return Err(BridgeError::Config(format!("Unknown RPC option \"{}\"", option)))
+    }
+  }
+  Ok(rpc_options)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_bridge_rpcs_works() {
+    assert_eq!(vec![
+      Rpc::new("replies", "rpc_replies"),
+      Rpc{ reply_queue: "totals".to_string(), table: "app.totals".to_string(),
+           options: RpcOptions{ notify: Some("totals".to_string()), timeout: Duration::from_secs(5), prefetch: 1 } }
+    ], parse_bridge_rpcs(" totals : app.totals ? notify=totals & timeout=5 & prefetch=1, replies:rpc_replies,").unwrap());
+    assert!(parse_bridge_rpcs("replies").is_err());
+    assert!(parse_bridge_rpcs("replies:rpc_replies; drop table rpc_replies").is_err());
+    assert!(parse_bridge_rpcs("replies:rpc_replies?timeout=0").is_err());
+    assert!(parse_bridge_rpcs("replies:rpc_replies?notify=").is_err());
+    assert!(parse_bridge_rpcs("replies:rpc_replies,replies:app.replies").is_err());
+  }
+
+  #[test]
+  fn calls_expire_after_their_timeout() {
+    let rpc = Rpc{ options: RpcOptions{ timeout: Duration::from_millis(50), ..RpcOptions::default() }, ..Rpc::new("replies", "rpc_replies") };
+    let calls = Calls::new(&[rpc]);
+    let call = |reply_to: &str, correlation_id: &str| BasicProperties::default().with_reply_to(reply_to.into()).with_correlation_id(correlation_id.into());
+    calls.sent(&call("replies", "1"));
+    calls.sent(&call("replies", "2"));
+    calls.sent(&call("elsewhere", "3"));
+    calls.sent(&BasicProperties::default().with_reply_to("replies".into()));
+    calls.replied("replies", "1");
+    assert!(calls.expired("replies").is_empty());
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(vec!["2".to_string()], calls.expired("replies"));
+    assert!(calls.expired("replies").is_empty());
+  }
+}
@@ -0,0 +1,96 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::SHUTDOWN_CHECK_INTERVAL;
+
+/*
+ * Shared by the listener and the publishers of a bridge. Once requested the listener stops taking notifications
+ * and the publishers keep delivering the pending ones until the deadline.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown{
+  deadline: Arc<Mutex<Option<Instant>>>
+}
+
+impl Shutdown {
+  pub fn new() -> Shutdown {
+    Shutdown::default()
+  }
+
+  // Only the first request sets the deadline
+  pub fn request(&self, timeout: Duration){
+    let mut deadline = self.deadline.lock().unwrap();
+    if deadline.is_none() {
+      *deadline = Some(Instant::now() + timeout);
+    }
+  }
+
+  pub fn is_requested(&self) -> bool {
+    self.deadline.lock().unwrap().is_some()
+  }
+
+  // Past the deadline there's no time left for publishing
+  pub fn is_overdue(&self) -> bool {
+    self.deadline.lock().unwrap().is_some_and(|deadline| Instant::now() >= deadline)
+  }
+
+  // Returns once the deadline has passed, the shutdown can be requested meanwhile
+  pub async fn overdue(&self){
+    loop {
+      let deadline = *self.deadline.lock().unwrap();
+      match deadline {
+        Some(deadline) => return tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)).await,
+        None => tokio::time::sleep(SHUTDOWN_CHECK_INTERVAL).await
+      }
+    }
+  }
+
+  // Sleeps for the given time or until the shutdown is requested
+  pub async fn sleep(&self, duration: Duration){
+    let until = Instant::now() + duration;
+    let mut now = Instant::now();
+    while now < until && !self.is_requested() {
+      tokio::time::sleep(cmp::min(SHUTDOWN_CHECK_INTERVAL, until - now)).await;
+      now = Instant::now();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shutdown_works() {
+    let shutdown = Shutdown::new();
+    assert!(!shutdown.is_requested());
+    assert!(!shutdown.is_overdue());
+    shutdown.clone().request(Duration::from_secs(60));
+    assert!(shutdown.is_requested());
+    assert!(!shutdown.is_overdue());
+    shutdown.request(Duration::from_secs(0));
+    assert!(!shutdown.is_overdue());
+
+    let shutdown = Shutdown::new();
+    shutdown.request(Duration::from_secs(0));
+    assert!(shutdown.is_overdue());
+  }
+
+  #[test]
+  fn overdue_waits_for_the_deadline() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let shutdown = Shutdown::new();
+    let start = Instant::now();
+    runtime.block_on(async {
+      let requested = shutdown.clone();
+      tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        requested.request(Duration::from_millis(200));
+      });
+      shutdown.overdue().await;
+    });
+    assert!(shutdown.is_overdue());
+    assert!(start.elapsed() >= Duration::from_millis(300));
+  }
+}
@@ -0,0 +1,366 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_postgres::Client;
+
+use super::{Binding, next_notification, publish_notification};
+use super::error::BridgeError;
+use super::publisher::Publisher;
+use super::shutdown::Shutdown;
+
+// Time between the attempts to reconnect and replay the spool while the broker is unreachable
+const REPLAY_INTERVAL: Duration = Duration::from_secs(5);
+// A full spool that drops its oldest messages makes room for a tenth of its size at once
+const DROP_OLDEST_HEADROOM: u64 = 10;
+
+// What happens to a message that doesn't fit in the spool
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Overflow {
+  // The new message takes the failure path of its binding
+  Reject,
+  // The oldest spooled messages are dropped
+  DropOldest
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpoolOptions{
+  // Directory of the spool files, one per notification binding
+  pub dir: PathBuf,
+  pub max_bytes: u64,
+  pub overflow: Overflow
+}
+
+impl SpoolOptions {
+  pub fn new(dir: &str) -> SpoolOptions {
+    SpoolOptions{ dir: PathBuf::from(dir), max_bytes: 100 * 1024 * 1024, overflow: Overflow::Reject }
+  }
+
+  // The directory is made and checked when the bridge starts rather than when the broker is lost
+  pub(crate) fn check(&self) -> Result<(), BridgeError> {
+    fs::create_dir_all(&self.dir)
+      .and_then(|_| fs::metadata(&self.dir))
+      .and_then(|metadata| if metadata.permissions().readonly() {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "read only"))
+      } else {
+        Ok(())
+      })
+      .map_err(|e| BridgeError::Config(format!("The spool directory {:?} can't be used: {}", self.dir, e)))?;
+    if self.max_bytes == 0 {
+      return Err(BridgeError::Config("The spool max_bytes must be at least 1".to_string()));
+    }
+    Ok(())
+  }
+}
+
+/*
+ * Write-ahead log of the notifications of a binding that couldn't be published, one json string per line so the
+ * payloads can have line breaks. The replayed messages are only removed from the file once the spool is empty or
+ * closed, so the ones replayed before a crash are published again.
+*/
+pub struct Spool{
+  path: PathBuf,
+  file: File,
+  len: u64,
+  // Start of the first message not replayed yet
+  offset: u64,
+  reader: Option<BufReader<File>>,
+  // Line of the first message once it's read
+  head: Option<String>,
+  options: SpoolOptions
+}
+
+impl Spool {
+  pub fn open(binding: &Binding, options: &SpoolOptions) -> io::Result<Spool> {
+    let path = options.dir.join(format!("{}-{}.spool", file_name_part(&binding.pg_channel), file_name_part(&binding.amqp_entity)));
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let len = file.metadata()?.len();
+    Ok(Spool{ path, file, len, offset: 0, reader: None, head: None, options: options.clone() })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.offset >= self.len
+  }
+
+  // Returns false when the message doesn't fit and the overflow policy rejects it
+  pub fn append(&mut self, notification: &str) -> io::Result<bool> {
+    let record = format!("{}\n", serde_json::to_string(notification)?);
+    let size = record.len() as u64;
+    if self.len - self.offset + size > self.options.max_bytes {
+      match self.options.overflow {
+        Overflow::DropOldest if size <= self.options.max_bytes => {
+          let target = self.options.max_bytes - self.options.max_bytes / DROP_OLDEST_HEADROOM;
+          let dropped = self.drop_oldest(target.saturating_sub(size))?;
+          warn!("The spool {:?} is full, dropped its {} oldest messages", self.path, dropped);
+        },
+        _ => return Ok(false)
+      }
+    }
+    self.file.write_all(record.as_bytes())?;
+    self.file.sync_data()?;
+    self.len += size;
+    Ok(true)
+  }
+
+  // The oldest message not replayed yet
+  pub fn front(&mut self) -> io::Result<Option<String>> {
+    if self.is_empty() {
+      return Ok(None);
+    }
+    if self.head.is_none() {
+      self.head = Some(self.read_line()?);
+    }
+    let notification = serde_json::from_str(self.head.as_ref().unwrap().trim_end())?;
+    Ok(Some(notification))
+  }
+
+  // Removes the front message once it's published, the file is emptied with the last one
+  pub fn pop(&mut self) -> io::Result<()> {
+    let line = match self.head.take() {
+      Some(line) => line,
+      None => self.read_line()?
+    };
+    self.offset += line.len() as u64;
+    if self.is_empty() {
+      self.truncate(self.len)?;
+    }
+    Ok(())
+  }
+
+  // Drops an unreadable message, e.g. the last one of a crash while it was written, with the rest when it can't be told apart
+  pub fn skip(&mut self) -> io::Result<()> {
+    if self.pop().is_err() {
+      let len = self.len;
+      self.truncate(len)?;
+    }
+    Ok(())
+  }
+
+  // Keeps the messages not replayed yet for the next start
+  pub fn close(mut self) {
+    let offset = self.offset;
+    if let Err(e) = self.truncate(offset) {
+      error!("Could not compact the spool {:?}: {}", self.path, e);
+    }
+  }
+
+  fn read_line(&mut self) -> io::Result<String> {
+    if self.reader.is_none() {
+      let mut file = File::open(&self.path)?;
+      file.seek(SeekFrom::Start(self.offset))?;
+      self.reader = Some(BufReader::new(file));
+    }
+    let mut line = String::new();
+    self.reader.as_mut().unwrap().read_line(&mut line)?;
+    if !line.ends_with('\n') {
+      return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Truncated message in the spool {:?}", self.path)));
+    }
+    Ok(line)
+  }
+
+  // Drops the messages before the offset, or all of them when they were all replayed
+  fn truncate(&mut self, offset: u64) -> io::Result<()> {
+    self.reader = None;
+    self.head = None;
+    if offset == 0 {
+      return Ok(());
+    }
+    let rest = if offset >= self.len { Vec::new() } else {
+      let mut file = File::open(&self.path)?;
+      file.seek(SeekFrom::Start(offset))?;
+      let mut rest = Vec::new();
+      file.read_to_end(&mut rest)?;
+      rest
+    };
+    let compacted = self.path.with_extension("spool.tmp");
+    let mut file = File::create(&compacted)?;
+    file.write_all(&rest)?;
+    file.sync_data()?;
+    fs::rename(&compacted, &self.path)?;
+    self.file = OpenOptions::new().append(true).open(&self.path)?;
+    self.len = rest.len() as u64;
+    self.offset = 0;
+    Ok(())
+  }
+
+  // Drops whole messages from the front until the spool takes at most the given bytes, returns how many
+  fn drop_oldest(&mut self, max_len: u64) -> io::Result<u64> {
+    let mut dropped = 0;
+    while !self.is_empty() && self.len - self.offset > max_len {
+      self.pop()?;
+      dropped += 1;
+    }
+    let offset = self.offset;
+    self.truncate(offset)?;
+    Ok(dropped)
+  }
+}
+
+// Percent-escapes everything but letters, digits, _ and ., so the - between the parts of a spool name can't be in them
+fn file_name_part(part: &str) -> String {
+  part.bytes().map(|b| if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' { (b as char).to_string() } else { format!("%{:02X}", b) }).collect()
+}
+
+/*
+ * Publishes the notifications of a binding, spooling them while the broker is unreachable instead of waiting for it.
+ * Once a message is spooled the next ones are spooled too, so they're all published in order when it's replayed.
+ * The connection is only tried again before a replay, the publishes are never cancelled once started.
+*/
+pub(crate) async fn relay_spooled(publisher: &mut Publisher, pg_client: &Client, binding: &Binding, mut spool: Spool,
+                                  notifications: &mut UnboundedReceiver<String>, shutdown: &Shutdown) {
+  publisher.set_waits_for_connection(false);
+  loop {
+    while !spool.is_empty() && !shutdown.is_requested() {
+      if !publisher.is_connected() {
+        if let Err(e) = publisher.reconnect().await {
+          warn!("{:?} -> {:?} keeping the messages spooled: {}", binding.pg_channel, binding.amqp_entity, e);
+          break;
+        }
+      }
+      let notification = match spool.front() {
+        Ok(Some(notification)) => notification,
+        Ok(None) => break,
+        Err(e) => {
+          error!("{:?} -> {:?} dropped an unreadable message of the spool: {}", binding.pg_channel, binding.amqp_entity, e);
+          if let Err(e) = spool.skip() {
+            error!("{:?} -> {:?} could not skip it: {}", binding.pg_channel, binding.amqp_entity, e);
+            break;
+          }
+          continue;
+        }
+      };
+      if !published(publisher, pg_client, binding, &notification, shutdown).await {
+        break;
+      }
+      if let Err(e) = spool.pop() {
+        error!("{:?} -> {:?} could not remove a replayed message from the spool: {}", binding.pg_channel, binding.amqp_entity, e);
+      }
+      if spool.is_empty() {
+        println!("Replayed the spool of {:?} -> {:?}", binding.pg_channel, binding.amqp_entity);
+      }
+    }
+    let notification = if spool.is_empty() || shutdown.is_requested() {
+      next_notification(publisher, notifications).await
+    } else {
+      tokio::select! {
+        notification = notifications.recv() => notification,
+        _ = shutdown.sleep(REPLAY_INTERVAL) => continue
+      }
+    };
+    let notification = match notification {
+      Some(notification) => notification,
+      None => break
+    };
+    // Past the shutdown deadline the messages are kept for the next start
+    if spool.is_empty() && !shutdown.is_overdue() && published(publisher, pg_client, binding, &notification, shutdown).await {
+      continue;
+    }
+    match spool.append(&notification) {
+      Ok(true) => {},
+      Ok(false) => publisher.send_invalid_payload_to_fallback(&notification, "spool full").await,
+      Err(e) => {
+        error!("{:?} -> {:?} could not spool a message: {}", binding.pg_channel, binding.amqp_entity, e);
+        publisher.send_invalid_payload_to_fallback(&notification, "spool error").await;
+      }
+    }
+  }
+  spool.close();
+}
+
+/*
+ * The messages the broker refuses still take the failure path, only an unreachable broker keeps them in the spool.
+ * A lost connection spools the message right away, without publishing it.
+*/
+async fn published(publisher: &mut Publisher, pg_client: &Client, binding: &Binding, notification: &str, shutdown: &Shutdown) -> bool {
+  if !publisher.is_connected() {
+    warn!("{:?} -> {:?} spooling the messages: the AMQP connection is lost", binding.pg_channel, binding.amqp_entity);
+    return false;
+  }
+  match publish_notification(publisher, pg_client, binding, notification, shutdown).await {
+    Ok(()) => true,
+    Err((e, _)) => {
+      warn!("{:?} -> {:?} spooling the messages: {}", binding.pg_channel, binding.amqp_entity, e);
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn open_spool(name: &str, max_bytes: u64, overflow: Overflow) -> Spool {
+    let dir = std::env::temp_dir().join(format!("pg-amqp-bridge-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let options = SpoolOptions{ dir, max_bytes, overflow };
+    options.check().unwrap();
+    Spool::open(&Binding::new("pg/channel", "exchange"), &options).unwrap()
+  }
+
+  fn replay(spool: &mut Spool) -> Vec<String> {
+    let mut notifications = Vec::new();
+    while let Some(notification) = spool.front().unwrap() {
+      notifications.push(notification);
+      spool.pop().unwrap();
+    }
+    notifications
+  }
+
+  #[test]
+  fn spool_replays_in_order() {
+    let mut spool = open_spool("order", 1024, Overflow::Reject);
+    assert!(spool.path.ends_with("pg%2Fchannel-exchange.spool"));
+    assert!(spool.is_empty());
+    assert!(spool.append("key|A message").unwrap());
+    assert!(spool.append("key|Two\nlines").unwrap());
+    assert_eq!(Some("key|A message".to_string()), spool.front().unwrap());
+    assert_eq!(Some("key|A message".to_string()), spool.front().unwrap());
+    spool.pop().unwrap();
+    assert!(spool.append("key|Last").unwrap());
+    assert_eq!(vec!["key|Two\nlines".to_string(), "key|Last".to_string()], replay(&mut spool));
+    assert!(spool.is_empty());
+    assert_eq!(0, fs::metadata(&spool.path).unwrap().len());
+  }
+
+  #[test]
+  fn spool_names_dont_collide() {
+    let options = open_spool("names", 1024, Overflow::Reject).options;
+    let path = |pg_channel: &str, amqp_entity: &str| Spool::open(&Binding::new(pg_channel, amqp_entity), &options).unwrap().path;
+    assert_ne!(path("pg/channel", "exchange"), path("pg_channel", "exchange"));
+    assert_ne!(path("a-b", "c"), path("a", "b-c"));
+    let mut spool = Spool::open(&Binding::new("a-b", "c"), &options).unwrap();
+    spool.append("key|A message").unwrap();
+    assert!(Spool::open(&Binding::new("a", "b-c"), &options).unwrap().is_empty());
+  }
+
+  #[test]
+  fn spool_keeps_the_rest_when_closed() {
+    let mut spool = open_spool("close", 1024, Overflow::Reject);
+    let options = spool.options.clone();
+    spool.append("1").unwrap();
+    spool.append("2").unwrap();
+    spool.pop().unwrap();
+    spool.close();
+    let mut spool = Spool::open(&Binding::new("pg/channel", "exchange"), &options).unwrap();
+    spool.append("3").unwrap();
+    assert_eq!(vec!["2".to_string(), "3".to_string()], replay(&mut spool));
+  }
+
+  #[test]
+  fn spool_overflow_works() {
+    // Every message takes 5 bytes, its json string and a line break
+    let mut spool = open_spool("reject", 12, Overflow::Reject);
+    assert!(spool.append("m1").unwrap());
+    assert!(spool.append("m2").unwrap());
+    assert!(!spool.append("m3").unwrap());
+    assert_eq!(vec!["m1".to_string(), "m2".to_string()], replay(&mut spool));
+
+    let mut spool = open_spool("drop_oldest", 30, Overflow::DropOldest);
+    for i in 1..=7 {
+      assert!(spool.append(&format!("m{}", i)).unwrap());
+    }
+    assert!(!spool.append(&"m".repeat(40)).unwrap());
+    assert_eq!((3..=7).map(|i| format!("m{}", i)).collect::<Vec<String>>(), replay(&mut spool));
+  }
+}
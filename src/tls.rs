@@ -0,0 +1,30 @@
+use native_tls::{Certificate, Identity, TlsConnector, TlsConnectorBuilder};
+
+use super::error::BridgeError;
+
+/*
+ * A TLS connector builder for the PostgreSQL or AMQP server, with their PEM root certificate and client
+ * certificate and key(in PKCS#8).
+*/
+pub fn connector_builder(server: &str, root_cert: Option<&str>, client_cert: Option<&str>, client_key: Option<&str>) -> Result<TlsConnectorBuilder, BridgeError> {
+  let mut builder = TlsConnector::builder();
+  if let Some(root_cert) = root_cert {
+    let root_cert = Certificate::from_pem(root_cert.as_bytes())
+      .map_err(|e| BridgeError::Config(format!("Invalid {} root certificate: {}", server, e)))?;
+    builder.add_root_certificate(root_cert);
+  }
+  match (client_cert, client_key) {
+    (Some(cert), Some(key)) => {
+      let identity = Identity::from_pkcs8(cert.as_bytes(), key.as_bytes())
+        .map_err(|e| BridgeError::Config(format!("Invalid {} client certificate or key: {}", server, e)))?;
+      builder.identity(identity);
+    },
+    (None, None) => {},
+    _ => return Err(BridgeError::Config(format!("The {} client certificate and key must be given together", server)))
+  }
+  Ok(builder)
+}
+
+pub fn build(server: &str, builder: &TlsConnectorBuilder) -> Result<TlsConnector, BridgeError> {
+  builder.build().map_err(|e| BridgeError::Config(format!("Invalid {} TLS settings: {}", server, e)))
+}
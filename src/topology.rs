@@ -0,0 +1,275 @@
+use lapin::{Connection, ExchangeKind};
+use lapin::options::{ExchangeBindOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use super::{Binding, Type};
+use super::amqp::close_channel;
+use super::error::BridgeError;
+
+/*
+ * Exchanges, queues and bindings the bridge declares every time it connects to the broker, before the bindings start.
+ * Declaring them again is a no-op as long as their settings don't change, otherwise the broker refuses it.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology{
+  #[serde(default)]
+  pub exchanges: Vec<Exchange>,
+  #[serde(default)]
+  pub queues: Vec<Queue>,
+  #[serde(default)]
+  pub bindings: Vec<TopologyBinding>
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exchange{
+  pub name: String,
+  #[serde(default)]
+  pub kind: Kind,
+  #[serde(default)]
+  pub durable: bool,
+  #[serde(default)]
+  pub auto_delete: bool,
+  #[serde(default)]
+  pub arguments: BTreeMap<String, Argument>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+  #[default]
+  Direct,
+  Fanout,
+  Topic,
+  Headers
+}
+
+// The dead letter, TTL and max length settings are shorthands for their x- arguments
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Queue{
+  pub name: String,
+  #[serde(default)]
+  pub durable: bool,
+  #[serde(default)]
+  pub auto_delete: bool,
+  pub dead_letter_exchange: Option<String>,
+  pub dead_letter_routing_key: Option<String>,
+  // Milliseconds
+  pub message_ttl: Option<u32>,
+  pub max_length: Option<u32>,
+  #[serde(default)]
+  pub arguments: BTreeMap<String, Argument>
+}
+
+// Routes the messages of the source exchange to the destination queue, or exchange
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopologyBinding{
+  pub source: String,
+  pub destination: String,
+  #[serde(default)]
+  pub destination_type: DestinationType,
+  #[serde(default)]
+  pub routing_key: String,
+  #[serde(default)]
+  pub arguments: BTreeMap<String, Argument>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DestinationType {
+  #[default]
+  Queue,
+  Exchange
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Argument {
+  Bool(bool),
+  Integer(i64),
+  Float(f64),
+  String(String)
+}
+
+impl Topology {
+  pub fn is_empty(&self) -> bool {
+    self.exchanges.is_empty() && self.queues.is_empty() && self.bindings.is_empty()
+  }
+
+  pub fn check(&self) -> Result<(), BridgeError> {
+    if self.exchanges.iter().any(|exchange| exchange.name.trim().is_empty()) ||
+       self.queues.iter().any(|queue| queue.name.trim().is_empty()) {
+      return Err(BridgeError::Config("Every exchange and queue of the topology must have a name".to_string()));
+    }
+    if self.bindings.iter().any(|binding| binding.source.trim().is_empty() || binding.destination.trim().is_empty()) {
+      return Err(BridgeError::Config("Every binding of the topology must have a source and a destination".to_string()));
+    }
+    Ok(())
+  }
+
+  // The type and exchange kind a binding states must agree with the declaration of its amqp entity
+  pub fn check_binding(&self, binding: &Binding) -> Result<(), BridgeError> {
+    let exchange = self.exchanges.iter().find(|exchange| exchange.name == binding.amqp_entity);
+    let queue = self.queues.iter().find(|queue| queue.name == binding.amqp_entity);
+    match (&binding.options.entity_type, exchange, queue) {
+      (Some(Type::Queue), Some(_), None) => return Err(BridgeError::EntityTypeMismatch(binding.amqp_entity.clone(), Type::Exchange, None)),
+      (Some(Type::Exchange), None, Some(_)) => return Err(BridgeError::EntityTypeMismatch(binding.amqp_entity.clone(), Type::Queue, None)),
+      _ => {}
+    }
+    match (binding.options.exchange_kind, exchange) {
+      (Some(kind), Some(exchange)) if kind != exchange.kind =>
+        Err(BridgeError::Config(format!("The binding of {:?} states a {:?} exchange but the topology declares {:?} as {:?}",
+                                        binding.pg_channel, kind, exchange.name, exchange.kind))),
+      _ => Ok(())
+    }
+  }
+}
+
+impl Kind {
+  pub fn exchange_kind(&self) -> ExchangeKind {
+    match self {
+      Kind::Direct => ExchangeKind::Direct,
+      Kind::Fanout => ExchangeKind::Fanout,
+      Kind::Topic => ExchangeKind::Topic,
+      Kind::Headers => ExchangeKind::Headers
+    }
+  }
+}
+
+impl Queue {
+  fn arguments(&self) -> FieldTable {
+    let mut arguments = field_table(&self.arguments);
+    if let Some(ref exchange) = self.dead_letter_exchange {
+      arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString(exchange.as_str().into()));
+    }
+    if let Some(ref routing_key) = self.dead_letter_routing_key {
+      arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(routing_key.as_str().into()));
+    }
+    if let Some(ttl) = self.message_ttl {
+      arguments.insert("x-message-ttl".into(), AMQPValue::LongLongInt(ttl.into()));
+    }
+    if let Some(max_length) = self.max_length {
+      arguments.insert("x-max-length".into(), AMQPValue::LongLongInt(max_length.into()));
+    }
+    arguments
+  }
+}
+
+fn field_table(arguments: &BTreeMap<String, Argument>) -> FieldTable {
+  let mut table = FieldTable::default();
+  for (name, argument) in arguments {
+    let value = match argument {
+      Argument::Bool(value) => AMQPValue::Boolean(*value),
+      Argument::Integer(value) => AMQPValue::LongLongInt(*value),
+      Argument::Float(value) => AMQPValue::Double(*value),
+      Argument::String(value) => AMQPValue::LongString(value.as_str().into())
+    };
+    table.insert(name.as_str().into(), value);
+  }
+  table
+}
+
+// Exchanges go first since the bindings need both of their ends
+pub async fn declare(connection: &Connection, topology: &Topology) -> Result<(), BridgeError> {
+  let channel = connection.create_channel().await?;
+  let declared = declare_on(&channel, topology).await;
+  close_channel(&channel).await;
+  declared?;
+  println!("Declared the AMQP topology: {} exchanges, {} queues, {} bindings",
+           topology.exchanges.len(), topology.queues.len(), topology.bindings.len());
+  Ok(())
+}
+
+// The broker errors mean a mismatch with what's already declared, which connecting again doesn't fix
+async fn declare_on(channel: &lapin::Channel, topology: &Topology) -> Result<(), BridgeError> {
+  let refused = |entity: String| move |e: lapin::Error| match e {
+    lapin::Error::ProtocolError(_) => BridgeError::Config(format!("The broker refused to declare {}: {}", entity, e)),
+    e => BridgeError::Amqp(e)
+  };
+  for exchange in &topology.exchanges {
+    let options = ExchangeDeclareOptions{ durable: exchange.durable, auto_delete: exchange.auto_delete, ..ExchangeDeclareOptions::default() };
+    channel.exchange_declare(&exchange.name, exchange.kind.exchange_kind(), options, field_table(&exchange.arguments)).await
+      .map_err(refused(format!("the exchange {:?}", exchange.name)))?;
+  }
+  for queue in &topology.queues {
+    let options = QueueDeclareOptions{ durable: queue.durable, auto_delete: queue.auto_delete, ..QueueDeclareOptions::default() };
+    channel.queue_declare(&queue.name, options, queue.arguments()).await
+      .map_err(refused(format!("the queue {:?}", queue.name)))?;
+  }
+  for binding in &topology.bindings {
+    let entity = format!("the binding of {:?} to {:?}", binding.source, binding.destination);
+    match binding.destination_type {
+      DestinationType::Queue =>
+        channel.queue_bind(&binding.destination, &binding.source, &binding.routing_key,
+                           QueueBindOptions::default(), field_table(&binding.arguments)).await,
+      DestinationType::Exchange =>
+        channel.exchange_bind(&binding.destination, &binding.source, &binding.routing_key,
+                              ExchangeBindOptions::default(), field_table(&binding.arguments)).await
+    }.map_err(refused(entity))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn queue_arguments_works() {
+    let queue = Queue{
+      name: "tasks".to_string(),
+      dead_letter_exchange: Some("dlx".to_string()),
+      message_ttl: Some(60000),
+      max_length: Some(1000),
+      arguments: btreemap!{
+        "x-queue-type".to_string() => Argument::String("quorum".to_string()),
+        "x-single-active-consumer".to_string() => Argument::Bool(true)
+      },
+      ..Queue::default()
+    };
+    assert_eq!(FieldTable::from(btreemap!{
+      "x-dead-letter-exchange".into() => AMQPValue::LongString("dlx".into()),
+      "x-message-ttl".into() => AMQPValue::LongLongInt(60000),
+      "x-max-length".into() => AMQPValue::LongLongInt(1000),
+      "x-queue-type".into() => AMQPValue::LongString("quorum".into()),
+      "x-single-active-consumer".into() => AMQPValue::Boolean(true)
+    }), queue.arguments());
+  }
+
+  #[test]
+  fn topology_check_fails_on_missing_names() {
+    assert!(Topology::default().check().is_ok());
+    assert!(Topology{ queues: vec![Queue::default()], ..Topology::default() }.check().is_err());
+    assert!(Topology{ bindings: vec![TopologyBinding{
+      source: "events".to_string(), destination: " ".to_string(), destination_type: DestinationType::Queue,
+      routing_key: "#".to_string(), arguments: BTreeMap::new()
+    }], ..Topology::default() }.check().is_err());
+  }
+
+  #[test]
+  fn topology_check_binding_works() {
+    let topology = Topology{
+      exchanges: vec![Exchange{ name: "billing".to_string(), kind: Kind::Topic, durable: true, auto_delete: false, arguments: BTreeMap::new() }],
+      queues: vec![Queue{ name: "invoices".to_string(), ..Queue::default() }],
+      bindings: Vec::new()
+    };
+    let binding = |amqp_entity: &str, entity_type: Option<Type>, exchange_kind: Option<Kind>| {
+      let mut binding = Binding::new("pgchannel1", amqp_entity);
+      binding.options.entity_type = entity_type;
+      binding.options.exchange_kind = exchange_kind;
+      binding
+    };
+    assert!(topology.check_binding(&binding("billing", None, None)).is_ok());
+    assert!(topology.check_binding(&binding("billing", Some(Type::Exchange), Some(Kind::Topic))).is_ok());
+    assert!(topology.check_binding(&binding("invoices", Some(Type::Queue), None)).is_ok());
+    assert!(topology.check_binding(&binding("elsewhere", Some(Type::Exchange), Some(Kind::Fanout))).is_ok());
+    assert!(topology.check_binding(&binding("billing", Some(Type::Queue), None)).is_err());
+    assert!(topology.check_binding(&binding("invoices", Some(Type::Exchange), None)).is_err());
+    assert!(topology.check_binding(&binding("billing", Some(Type::Exchange), Some(Kind::Fanout))).is_err());
+  }
+}
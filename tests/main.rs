@@ -4,11 +4,7 @@ extern crate futures;
 extern crate tokio_core;
 extern crate lapin_futures as lapin;
 extern crate postgres;
-extern crate r2d2;
-extern crate r2d2_postgres;
 
-use r2d2::{Pool};
-use r2d2_postgres::{PostgresConnectionManager};
 use postgres::{NoTls};
 use futures::*;
 use tokio_core::reactor::Core;
@@ -16,17 +12,23 @@ use tokio_core::net::TcpStream;
 //use lapin_futures::types::AMQPType::FieldArray;
 use lapin_futures::types::AMQPValue::FieldArray;
 use lapin_futures::types::AMQPValue::LongString;
+use lapin_futures::types::AMQPValue::LongLongInt;
 use lapin::client::*;
 use lapin::channel::*;
 use lapin::types::FieldTable;
 use std::env;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use bridge::{Binding, Bridge, BridgeHandle, BindingState, Type};
+use bridge::topology::Kind;
 use rustc_test::*;
 
 //Lapin doesn't support amqp://localhost// format.
 const TEST_AMQP_HOST_PORT: &str = "127.0.0.1:5672";
 const TEST_AMQP_URI: &str = "amqp://localhost//";
+// No broker listens on it
+const TEST_DOWN_AMQP_URI: &str = "amqp://localhost:5673//";
 const TEST_PG_URI: &str = "postgres://postgres@localhost";
 
 const TEST_1_PG_CHANNEL: &str = "test_1_pgchannel";
@@ -40,6 +42,43 @@ const TEST_3_PG_CHANNEL: &str = "test_3_pgchannel";
 const TEST_3_QUEUE: &str = "test_3_queue";
 const TEST_3_EXCHANGE: &str = "test_3_topic_exchange";
 
+const TEST_4_PG_CHANNEL: &str = "test_4_pgchannel";
+const TEST_4_QUEUE: &str = "test_4_queue";
+
+const TEST_5_PG_CHANNEL: &str = "test_5_pgchannel";
+const TEST_5_EXCHANGE: &str = "test_5_unbound_exchange";
+const TEST_5_TABLE: &str = "test_5_returned_messages";
+const TEST_5_UNCONFIRMED_PG_CHANNEL: &str = "test_5_unconfirmed_pgchannel";
+
+const TEST_6_PG_CHANNEL: &str = "test_6_pgchannel";
+const TEST_6_QUEUE: &str = "test_6_queue";
+const TEST_6_OUTBOX: &str = "test_6_outbox";
+const TEST_6_UNROUTABLE_PG_CHANNEL: &str = "test_6_unroutable_pgchannel";
+
+const TEST_7_PG_CHANNEL: &str = "test_7_pgchannel";
+const TEST_7_QUEUE: &str = "test_7_queue";
+const TEST_7_TABLE: &str = "test_7_rows";
+const TEST_7_SLOT: &str = "test_7_slot";
+
+const TEST_8_PG_CHANNEL: &str = "test_8_pgchannel";
+const TEST_8_QUEUE: &str = "test_8_queue";
+
+const TEST_9_PG_CHANNEL: &str = "test_9_pgchannel";
+const TEST_9_QUEUE: &str = "test_9_queue";
+const TEST_9_EXCHANGE: &str = "test_9_direct_exchange";
+
+const TEST_10_PG_CHANNEL: &str = "test_10_pgchannel";
+const TEST_10_QUEUE: &str = "test_10_queue";
+
+const TEST_11_PG_CHANNEL: &str = "test_11_pgchannel";
+const TEST_11_KIND_PG_CHANNEL: &str = "test_11_kind_pgchannel";
+
+const TEST_12_PG_CHANNEL: &str = "test_12_pgchannel";
+const TEST_12_QUEUE_A: &str = "test_12_queue_a";
+const TEST_12_QUEUE_B: &str = "test_12_queue_b";
+// Never declared
+const TEST_12_EXCHANGE: &str = "test_12_missing_exchange";
+
 /*
  * Lapin is used for all the tests since rust-amqp has no way to exit a consumer
  * without panicking, this doesn't let the tests do assert! reliably.
@@ -155,12 +194,315 @@ fn publishing_to_topic_exchange_works() {
   );
 }
 
+fn publishing_with_confirms_works() {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|
+      channel.queue_declare(TEST_4_QUEUE, &QueueDeclareOptions::default(), FieldTable::new())
+      .and_then(move |_|
+        channel.basic_consume(TEST_4_QUEUE, "my_consumer_4", &BasicConsumeOptions::default())
+        .and_then(move |stream|{
+          pg_conn.execute(format!("NOTIFY {}, 'Confirmed queue test'", TEST_4_PG_CHANNEL).as_str(), &[]).unwrap();
+          stream.into_future().map_err(|(err, _)| err)
+          .and_then(move |(message, _)| {
+            let msg = message.unwrap();
+            assert_eq!(msg.data, b"Confirmed queue test");
+            channel.basic_ack(msg.delivery_tag)
+          })
+        })
+      )
+    )
+  );
+}
+
+fn unroutable_messages_go_to_fallback_table() {
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+  pg_conn.execute(format!("NOTIFY {}, 'no_queue_key|Unroutable message'", TEST_5_PG_CHANNEL).as_str(), &[]).unwrap();
+  thread::sleep(Duration::from_secs(1));
+  let rows = pg_conn.query(format!("SELECT routing_key, reason, message FROM {} WHERE pg_channel = $1", TEST_5_TABLE).as_str(), &[&TEST_5_PG_CHANNEL]).unwrap();
+  assert_eq!(rows.len(), 1);
+  assert_eq!(rows[0].get::<_, String>(0), "no_queue_key");
+  assert_eq!(rows[0].get::<_, String>(1), "NO_ROUTE");
+  assert_eq!(rows[0].get::<_, String>(2), "Unroutable message");
+}
+
+fn unroutable_messages_without_confirm_go_to_fallback_table() {
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+  pg_conn.execute(format!("NOTIFY {}, 'no_queue_key|Unconfirmed unroutable message'", TEST_5_UNCONFIRMED_PG_CHANNEL).as_str(), &[]).unwrap();
+  // The returned messages are handled once the binding is idle
+  thread::sleep(Duration::from_secs(3));
+  let rows = pg_conn.query(format!("SELECT routing_key, reason, message FROM {} WHERE pg_channel = $1", TEST_5_TABLE).as_str(), &[&TEST_5_UNCONFIRMED_PG_CHANNEL]).unwrap();
+  assert_eq!(rows.len(), 1);
+  assert_eq!(rows[0].get::<_, String>(0), "no_queue_key");
+  assert_eq!(rows[0].get::<_, String>(1), "NO_ROUTE");
+  assert_eq!(rows[0].get::<_, String>(2), "Unconfirmed unroutable message");
+}
+
+fn publishing_from_outbox_works() {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|
+      channel.queue_declare(TEST_6_QUEUE, &QueueDeclareOptions::default(), FieldTable::new())
+      .and_then(move |_|
+        channel.basic_consume(TEST_6_QUEUE, "my_consumer_6", &BasicConsumeOptions::default())
+        .and_then(move |stream|{
+          pg_conn.batch_execute(format!("INSERT INTO {0}(channel, payload) VALUES ('{1}', 'Outbox test'); NOTIFY {1};",
+                                        TEST_6_OUTBOX, TEST_6_PG_CHANNEL).as_str()).unwrap();
+          stream.into_future().map_err(|(err, _)| err)
+          .and_then(move |(message, _)| {
+            let msg = message.unwrap();
+            assert_eq!(msg.data, b"Outbox test");
+            thread::sleep(Duration::from_millis(500));
+            let remaining: i64 = pg_conn.query_one(format!("SELECT count(*) FROM {} WHERE channel = $1", TEST_6_OUTBOX).as_str(), &[&TEST_6_PG_CHANNEL]).unwrap().get(0);
+            assert_eq!(remaining, 0);
+            channel.basic_ack(msg.delivery_tag)
+          })
+        })
+      )
+    )
+  );
+}
+
+fn returned_outbox_rows_without_fallback_are_kept() {
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+  pg_conn.batch_execute(format!("INSERT INTO {0}(channel, payload) VALUES ('{1}', 'no_queue_key|Unroutable outbox test'); NOTIFY {1};",
+                                TEST_6_OUTBOX, TEST_6_UNROUTABLE_PG_CHANNEL).as_str()).unwrap();
+  thread::sleep(Duration::from_secs(1));
+  let remaining: i64 = pg_conn.query_one(format!("SELECT count(*) FROM {} WHERE channel = $1", TEST_6_OUTBOX).as_str(), &[&TEST_6_UNROUTABLE_PG_CHANNEL]).unwrap().get(0);
+  assert_eq!(remaining, 1);
+}
+
+fn publishing_row_changes_works() {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|
+      channel.queue_declare(TEST_7_QUEUE, &QueueDeclareOptions::default(), FieldTable::new())
+      .and_then(move |_|
+        channel.basic_consume(TEST_7_QUEUE, "my_consumer_7", &BasicConsumeOptions::default())
+        .and_then(move |stream|{
+          pg_conn.batch_execute(format!("INSERT INTO {} VALUES (1, 'Row change test'); NOTIFY {};",
+                                        TEST_7_TABLE, TEST_7_PG_CHANNEL).as_str()).unwrap();
+          stream.into_future().map_err(|(err, _)| err)
+          .and_then(move |(message, _)| {
+            let msg = message.unwrap();
+            assert_eq!(msg.data, br#"{"id":1,"name":"Row change test"}"#.to_vec());
+            channel.basic_ack(msg.delivery_tag)
+          })
+        })
+      )
+    )
+  );
+}
+
+fn publishing_json_payloads_with_properties_works() {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|
+      channel.queue_declare(TEST_8_QUEUE, &QueueDeclareOptions::default(), FieldTable::new())
+      .and_then(move |_|
+        channel.basic_consume(TEST_8_QUEUE, "my_consumer_8", &BasicConsumeOptions::default())
+        .and_then(move |stream|{
+          pg_conn.execute(format!(r#"NOTIFY {}, '{{"headers": {{"X-Retries": 3}}, "properties": {{"correlation_id": "42", "priority": 5}}, "body": {{"id": 1}}}}'"#,
+                                  TEST_8_PG_CHANNEL).as_str(), &[]).unwrap();
+          stream.into_future().map_err(|(err, _)| err)
+          .and_then(move |(message, _)| {
+            let msg = message.unwrap();
+            assert_eq!(msg.data, br#"{"id":1}"#);
+            assert_eq!(msg.properties.content_type, Some("application/json".to_string()));
+            assert_eq!(msg.properties.app_id, Some("test_8_app".to_string()));
+            assert_eq!(msg.properties.correlation_id, Some("42".to_string()));
+            assert_eq!(msg.properties.priority, Some(5));
+            assert_eq!(msg.properties.headers.unwrap().get("X-Retries"), Some(&LongLongInt(3)));
+            channel.basic_ack(msg.delivery_tag)
+          })
+        })
+      )
+    )
+  );
+}
+
+fn publishing_with_default_routing_key_works() {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|{
+      let ch1 = channel.clone();
+      let ch2 = channel.clone();
+      channel.queue_declare(TEST_9_QUEUE, &QueueDeclareOptions::default(), FieldTable::new())
+      .and_then(move |_| channel.queue_bind(TEST_9_QUEUE, TEST_9_EXCHANGE, "tenant1.audit.default", &QueueBindOptions::default(), FieldTable::new()))
+      .and_then(move |_| ch1.basic_consume(TEST_9_QUEUE, "my_consumer_9", &BasicConsumeOptions::default()))
+      .and_then(move |stream| {
+        let _ = pg_conn.execute(format!("NOTIFY {}, 'Default routing key test'", TEST_9_PG_CHANNEL).as_str(), &[]);
+        stream.into_future().map_err(|(err, _)| err)
+      })
+      .and_then(move |(message, _)| {
+        let msg = message.unwrap();
+        assert_eq!(msg.data, b"Default routing key test");
+        assert_eq!(msg.routing_key, "tenant1.audit.default");
+        assert_eq!(msg.properties.delivery_mode, Some(2));
+        ch2.basic_ack(msg.delivery_tag)
+      })
+    })
+  );
+}
+
+fn adding_a_binding_at_runtime_works(bridge_handle: Arc<BridgeHandle>) {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  bridge_handle.add_binding(Binding::new(TEST_10_PG_CHANNEL, TEST_10_QUEUE)).unwrap();
+  thread::sleep(Duration::from_secs(2));
+  let status = bridge_handle.status().into_iter().find(|status| status.pg_channel == TEST_10_PG_CHANNEL).unwrap();
+  assert_eq!(status.state, BindingState::Running);
+  // The first node is down
+  assert_eq!(status.amqp_node, Some("localhost:5672".to_string()));
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|
+      channel.basic_consume(TEST_10_QUEUE, "my_consumer_10", &BasicConsumeOptions::default())
+      .and_then(move |stream|{
+        pg_conn.execute(format!("NOTIFY {}, 'Runtime binding test'", TEST_10_PG_CHANNEL).as_str(), &[]).unwrap();
+        stream.into_future().map_err(|(err, _)| err)
+        .and_then(move |(message, _)| {
+          let msg = message.unwrap();
+          assert_eq!(msg.data, b"Runtime binding test");
+          channel.basic_ack(msg.delivery_tag)
+        })
+      })
+    )
+  );
+
+  bridge_handle.remove_binding(TEST_10_PG_CHANNEL).unwrap();
+  assert!(bridge_handle.status().iter().all(|status| status.pg_channel != TEST_10_PG_CHANNEL));
+}
+
+fn binding_with_the_wrong_entity_type_fails(bridge_handle: Arc<BridgeHandle>) {
+  let mut binding = Binding::new(TEST_11_PG_CHANNEL, TEST_1_QUEUE);
+  binding.options.entity_type = Some(Type::Exchange);
+  bridge_handle.add_binding(binding).unwrap();
+  thread::sleep(Duration::from_secs(2));
+  let status = bridge_handle.status().into_iter().find(|status| status.pg_channel == TEST_11_PG_CHANNEL).unwrap();
+  assert_eq!(status.state, BindingState::Failed(format!("The amqp entity {:?} is a queue, not an exchange", TEST_1_QUEUE)));
+  bridge_handle.remove_binding(TEST_11_PG_CHANNEL).unwrap();
+}
+
+fn binding_with_the_wrong_exchange_kind_fails(bridge_handle: Arc<BridgeHandle>) {
+  let mut binding = Binding::new(TEST_11_KIND_PG_CHANNEL, TEST_2_EXCHANGE);
+  binding.options.entity_type = Some(Type::Exchange);
+  binding.options.exchange_kind = Some(Kind::Topic);
+  bridge_handle.add_binding(binding).unwrap();
+  thread::sleep(Duration::from_secs(2));
+  let status = bridge_handle.status().into_iter().find(|status| status.pg_channel == TEST_11_KIND_PG_CHANNEL).unwrap();
+  assert_eq!(status.state, BindingState::Failed(format!("The amqp entity {:?} is not a topic exchange", TEST_2_EXCHANGE)));
+  bridge_handle.remove_binding(TEST_11_KIND_PG_CHANNEL).unwrap();
+}
+
+fn publishing_to_several_targets_works(bridge_handle: Arc<BridgeHandle>) {
+  let mut core = Core::new().unwrap();
+  let handle = core.handle();
+  let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
+
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+
+  // The missing exchange is skipped, the queues still get the messages
+  let missing = bridge_handle.status().into_iter().find(|status| status.amqp_entity == TEST_12_EXCHANGE).unwrap();
+  assert_eq!(missing.state, BindingState::Failed(format!("The amqp entity {:?} doesn't exist", TEST_12_EXCHANGE)));
+
+  let _ = core.run(
+    TcpStream::connect(&addr, &handle)
+    .and_then(|stream| Client::connect(stream, &ConnectionOptions::default()) )
+    .and_then(|client| client.create_channel())
+    .and_then(|channel|
+      channel.basic_consume(TEST_12_QUEUE_A, "my_consumer_12_a", &BasicConsumeOptions::default())
+      .and_then(move |stream_a|{
+        pg_conn.execute(format!("NOTIFY {}, 'Fan out test'", TEST_12_PG_CHANNEL).as_str(), &[]).unwrap();
+        stream_a.into_future().map_err(|(err, _)| err)
+        .and_then(move |(message, _)| {
+          let msg = message.unwrap();
+          assert_eq!(msg.data, b"Fan out test");
+          assert_eq!(msg.properties.delivery_mode, Some(1));
+          channel.basic_ack(msg.delivery_tag)
+          .and_then(move |_| channel.basic_consume(TEST_12_QUEUE_B, "my_consumer_12_b", &BasicConsumeOptions::default()).map(|stream_b| (channel, stream_b)))
+        })
+      })
+      .and_then(|(channel, stream_b)|
+        stream_b.into_future().map_err(|(err, _)| err)
+        .and_then(move |(message, _)| {
+          let msg = message.unwrap();
+          assert_eq!(msg.data, b"Fan out test");
+          assert_eq!(msg.properties.delivery_mode, Some(2));
+          channel.basic_ack(msg.delivery_tag)
+        })
+      )
+    )
+  );
+}
+
 /*
  * This is to pass validation of the bridge, the queues still need to be
  * redeclared in the tests due to the inability of using an undeclared queue in a channel
  * See this issue: https://github.com/sozu-proxy/lapin/issues/32
 */
 fn setup(){
+  let mut pg_conn = postgres::Client::connect(TEST_PG_URI, NoTls).unwrap();
+  pg_conn.batch_execute(format!("DROP TABLE IF EXISTS {0};
+                                 CREATE TABLE {0}(pg_channel text, amqp_entity text, routing_key text, reason text, message text);",
+                                TEST_5_TABLE).as_str()).unwrap();
+  pg_conn.batch_execute(format!("DROP TABLE IF EXISTS {0};
+                                 CREATE TABLE {0}(id bigserial primary key, channel text not null, payload text not null);",
+                                TEST_6_OUTBOX).as_str()).unwrap();
+  pg_conn.batch_execute(format!("SELECT pg_drop_replication_slot(slot_name) FROM pg_replication_slots WHERE slot_name = '{1}';
+                                 DROP PUBLICATION IF EXISTS {1};
+                                 DROP TABLE IF EXISTS {0};
+                                 CREATE TABLE {0}(id int primary key, name text);
+                                 CREATE PUBLICATION {1} FOR TABLE {0};",
+                                TEST_7_TABLE, TEST_7_SLOT).as_str()).unwrap();
+
   let mut core = Core::new().unwrap();
   let handle = core.handle();
   let addr = TEST_AMQP_HOST_PORT.parse().unwrap();
@@ -171,7 +513,14 @@ fn setup(){
     .and_then(|client| client.create_channel())
     .and_then(|channel|
       channel.queue_declare(TEST_1_QUEUE, &QueueDeclareOptions::default(), FieldTable::new())
-      .and_then(move |_| 
+      .and_then(move |_| channel.queue_declare(TEST_4_QUEUE, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel| channel.queue_declare(TEST_6_QUEUE, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel| channel.queue_declare(TEST_7_QUEUE, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel| channel.queue_declare(TEST_8_QUEUE, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel| channel.queue_declare(TEST_10_QUEUE, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel| channel.queue_declare(TEST_12_QUEUE_A, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel| channel.queue_declare(TEST_12_QUEUE_B, &QueueDeclareOptions::default(), FieldTable::new()).map(|_| channel))
+      .and_then(move |channel|
         channel.exchange_declare(TEST_2_EXCHANGE, "direct", 
                                  &ExchangeDeclareOptions{
                                    passive: false,
@@ -191,6 +540,28 @@ fn setup(){
                                      nowait: false,
                                      ..Default::default()
                                    }, FieldTable::new())
+          .and_then(move |_|
+            channel.exchange_declare(TEST_5_EXCHANGE, "direct",
+                                     &ExchangeDeclareOptions{
+                                       passive: false,
+                                       durable: false,
+                                       auto_delete: false,
+                                       internal: false,
+                                       nowait: false,
+                                       ..Default::default()
+                                     }, FieldTable::new())
+            .and_then(move |_|
+              channel.exchange_declare(TEST_9_EXCHANGE, "direct",
+                                       &ExchangeDeclareOptions{
+                                         passive: false,
+                                         durable: false,
+                                         auto_delete: false,
+                                         internal: false,
+                                         nowait: false,
+                                         ..Default::default()
+                                       }, FieldTable::new())
+            )
+          )
         )
       )
     )
@@ -212,6 +583,36 @@ fn teardown(){
         channel.queue_delete(TEST_2_QUEUE, &QueueDeleteOptions::default())
         .and_then(move |_| 
           channel.queue_delete(TEST_3_QUEUE, &QueueDeleteOptions::default())
+          .and_then(move |_|
+            channel.queue_delete(TEST_4_QUEUE, &QueueDeleteOptions::default())
+            .and_then(move |_|
+              channel.exchange_delete(TEST_5_EXCHANGE, &ExchangeDeleteOptions::default())
+              .and_then(move |_|
+                channel.queue_delete(TEST_6_QUEUE, &QueueDeleteOptions::default())
+                .and_then(move |_|
+                  channel.queue_delete(TEST_7_QUEUE, &QueueDeleteOptions::default())
+                  .and_then(move |_|
+                    channel.queue_delete(TEST_8_QUEUE, &QueueDeleteOptions::default())
+                    .and_then(move |_|
+                      channel.queue_delete(TEST_9_QUEUE, &QueueDeleteOptions::default())
+                      .and_then(move |_|
+                        channel.exchange_delete(TEST_9_EXCHANGE, &ExchangeDeleteOptions::default())
+                        .and_then(move |_|
+                          channel.queue_delete(TEST_10_QUEUE, &QueueDeleteOptions::default())
+                          .and_then(move |_|
+                            channel.queue_delete(TEST_12_QUEUE_A, &QueueDeleteOptions::default())
+                            .and_then(move |_|
+                              channel.queue_delete(TEST_12_QUEUE_B, &QueueDeleteOptions::default())
+                            )
+                          )
+                        )
+                      )
+                    )
+                  )
+                )
+              )
+            )
+          )
         )
       )
     })
@@ -234,25 +635,63 @@ fn main() {
   let args: Vec<_> = env::args().collect();
   let mut tests = Vec::new();
 
-  let bridge_channels = format!("{}:{},{}:{},{}:{}", 
+  let bridge_channels = format!("{}:queue:{},{}:exchange:{}?kind=direct,{}:{},{}:{}?confirm,{}:{}?confirm&fallback_table={},{}:{}?fallback_table={},{}:{}?outbox={},{}:{}?outbox={},{}:{}?slot={},{}:{}?format=json&content_type=application/json&app_id=test_8_app,{}:{}?persistent&key=audit.default&prefix=tenant1.,{}:{},{}:{}?persistent,{}:exchange:{}?on_failure=skip", 
                                 TEST_1_PG_CHANNEL, TEST_1_QUEUE,
                                 TEST_2_PG_CHANNEL, TEST_2_EXCHANGE,
-                                TEST_3_PG_CHANNEL, TEST_3_EXCHANGE);
+                                TEST_3_PG_CHANNEL, TEST_3_EXCHANGE,
+                                TEST_4_PG_CHANNEL, TEST_4_QUEUE,
+                                TEST_5_PG_CHANNEL, TEST_5_EXCHANGE, TEST_5_TABLE,
+                                TEST_5_UNCONFIRMED_PG_CHANNEL, TEST_5_EXCHANGE, TEST_5_TABLE,
+                                TEST_6_PG_CHANNEL, TEST_6_QUEUE, TEST_6_OUTBOX,
+                                TEST_6_UNROUTABLE_PG_CHANNEL, TEST_5_EXCHANGE, TEST_6_OUTBOX,
+                                TEST_7_PG_CHANNEL, TEST_7_QUEUE, TEST_7_SLOT,
+                                TEST_8_PG_CHANNEL, TEST_8_QUEUE,
+                                TEST_9_PG_CHANNEL, TEST_9_EXCHANGE,
+                                TEST_12_PG_CHANNEL, TEST_12_QUEUE_A,
+                                TEST_12_PG_CHANNEL, TEST_12_QUEUE_B,
+                                TEST_12_PG_CHANNEL, TEST_12_EXCHANGE);
 
   setup();
+
+  let bridge_handle = Arc::new(Bridge::builder()
+    .postgresql_uri(TEST_PG_URI)
+    .amqp_uris(vec![TEST_DOWN_AMQP_URI.to_string(), TEST_AMQP_URI.to_string()])
+    .bindings(bridge::parse_bridge_channels(&bridge_channels).unwrap())
+    .start()
+    .unwrap());
+
   add_test(&mut tests, "publishing_to_queue_works".to_string(), publishing_to_queue_works);
   add_test(&mut tests, "publishing_to_direct_exchange_works".to_string(), publishing_to_direct_exchange_works);
   add_test(&mut tests, "publishing_to_topic_exchange_works".to_string(), publishing_to_topic_exchange_works);
+  add_test(&mut tests, "publishing_with_confirms_works".to_string(), publishing_with_confirms_works);
+  add_test(&mut tests, "unroutable_messages_go_to_fallback_table".to_string(), unroutable_messages_go_to_fallback_table);
+  add_test(&mut tests, "unroutable_messages_without_confirm_go_to_fallback_table".to_string(), unroutable_messages_without_confirm_go_to_fallback_table);
+  add_test(&mut tests, "publishing_from_outbox_works".to_string(), publishing_from_outbox_works);
+  add_test(&mut tests, "returned_outbox_rows_without_fallback_are_kept".to_string(), returned_outbox_rows_without_fallback_are_kept);
+  add_test(&mut tests, "publishing_row_changes_works".to_string(), publishing_row_changes_works);
+  add_test(&mut tests, "publishing_json_payloads_with_properties_works".to_string(), publishing_json_payloads_with_properties_works);
+  add_test(&mut tests, "publishing_with_default_routing_key_works".to_string(), publishing_with_default_routing_key_works);
+  {
+    let bridge_handle = bridge_handle.clone();
+    add_test(&mut tests, "adding_a_binding_at_runtime_works".to_string(), move || adding_a_binding_at_runtime_works(bridge_handle));
+  }
+  {
+    let bridge_handle = bridge_handle.clone();
+    add_test(&mut tests, "binding_with_the_wrong_entity_type_fails".to_string(), move || binding_with_the_wrong_entity_type_fails(bridge_handle));
+  }
+  {
+    let bridge_handle = bridge_handle.clone();
+    add_test(&mut tests, "binding_with_the_wrong_exchange_kind_fails".to_string(), move || binding_with_the_wrong_exchange_kind_fails(bridge_handle));
+  }
+  {
+    let bridge_handle = bridge_handle.clone();
+    add_test(&mut tests, "publishing_to_several_targets_works".to_string(), move || publishing_to_several_targets_works(bridge_handle));
+  }
 
-  let pool = Pool::builder()
-    .connection_timeout(Duration::from_secs(1))
-    .build(PostgresConnectionManager::new(TEST_PG_URI.to_string().parse().unwrap(), NoTls))
-    .unwrap();
-  thread::spawn(move ||
-    bridge::start(pool, &TEST_AMQP_URI, &bridge_channels, &(1 as u8))
-  );
   thread::sleep(Duration::from_secs(4));
   test::test_main(&args, tests);
+  bridge_handle.stop();
+  bridge_handle.join().unwrap();
   teardown();
 }
 